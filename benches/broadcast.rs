@@ -0,0 +1,90 @@
+// Fan-out throughput of a single chat message to many connected peers, up to the
+// websocket frame each session writes
+
+use std::sync::Arc;
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures_channel::mpsc::{unbounded, UnboundedReceiver};
+use futures_util::{FutureExt, StreamExt};
+use tensor::{
+    message::{Broadcast, Message},
+    server::Tx,
+};
+use tokio_tungstenite::tungstenite::Message as Frame;
+
+struct Peer {
+    uuid: Arc<str>,
+    tx: Tx,
+    rx: UnboundedReceiver<Arc<str>>,
+}
+
+fn peers(count: usize) -> Vec<Peer> {
+    (0..count)
+        .map(|i| {
+            let (tx, rx) = unbounded();
+            Peer {
+                uuid: format!("{:03}-{:03}-{:03}-{:03}-", i % 1000, i / 1000, 0, 0).into(),
+                tx,
+                rx,
+            }
+        })
+        .collect()
+}
+
+// Sessions copy the payload into a frame of their own, as in websocket.rs
+fn drain(peers: &mut [Peer]) {
+    for peer in peers.iter_mut() {
+        while let Some(Some(payload)) = peer.rx.next().now_or_never() {
+            black_box(Frame::text(payload.as_ref()));
+        }
+    }
+}
+
+fn message(peers: &[Peer]) -> Message {
+    Message::new(
+        format!(
+            "hey <<!{}>> and <<!{}>>, check this out",
+            peers[0].uuid, peers[1].uuid
+        ),
+        peers[2].uuid.clone(),
+    )
+}
+
+fn fan_out(c: &mut Criterion) {
+    let mut group = c.benchmark_group("fan_out");
+    for count in [100, 250, 500] {
+        let mut peers = peers(count);
+        let message = message(&peers);
+        group.throughput(Throughput::Elements(count as u64));
+
+        // Previous behaviour: clone and serialize the message for every recipient
+        group.bench_with_input(BenchmarkId::new("per_recipient", count), &count, |b, _| {
+            b.iter(|| {
+                let mentions = message.mentions();
+                for peer in peers.iter() {
+                    let m = if mentions.contains(&peer.uuid.to_string()) {
+                        message.clone().set_mention()
+                    } else {
+                        message.clone()
+                    };
+                    let _ = peer.tx.unbounded_send(m.to_payload());
+                }
+                drain(&mut peers);
+            })
+        });
+
+        group.bench_with_input(BenchmarkId::new("shared", count), &count, |b, _| {
+            b.iter(|| {
+                let broadcast = Broadcast::new(&message);
+                for peer in peers.iter() {
+                    let _ = peer.tx.unbounded_send(broadcast.payload_for(&peer.uuid));
+                }
+                drain(&mut peers);
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, fan_out);
+criterion_main!(benches);
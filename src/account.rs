@@ -0,0 +1,225 @@
+// File Contains the Personal Data Export and Deletion of Client Accounts
+
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use sqlite::{Connection, Value};
+use tracing::error;
+
+use crate::{
+    attachment::Attachment,
+    blob::Blob,
+    client::{Client, PastUsername},
+    message::Message,
+    reaction::Reaction,
+    server::unix_time,
+    token::Token,
+};
+
+// Author of messages kept after their account was deleted
+pub const DELETED_USER_UUID: &str = "000-000-000-001-";
+// Messages looked up per query while exporting
+const EXPORT_BATCH: usize = 500;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeletedMessages {
+    // Messages stay with their attachments, credited to the deleted user
+    #[default]
+    Anonymize,
+    // Messages, their attachments and the reactions on them go with the account
+    Remove,
+}
+
+// A reaction the client added, as opposed to the ones on its messages
+#[derive(Debug, Clone, Serialize)]
+pub struct OwnReaction {
+    pub message_uuid: Arc<str>,
+    pub emoji: String,
+    pub unix_time: u64,
+}
+
+// Everything the server holds about a client. Attachment contents are
+// downloaded from /attachments/<attachment id>.
+#[derive(Debug, Clone, Serialize)]
+pub struct AccountExport {
+    pub exported_at: u64,
+    pub profile: Client,
+    pub past_usernames: Vec<PastUsername>,
+    pub tokens: Vec<Token>,
+    pub messages: Vec<Message>,
+    pub reactions: Vec<OwnReaction>,
+    pub attachments: Vec<Attachment>,
+}
+
+impl AccountExport {
+    pub fn new(connection: &Connection, client: Client) -> Self {
+        let uuid = client.get_uuid();
+        Self {
+            exported_at: unix_time(),
+            past_usernames: Client::username_history(connection, &uuid),
+            tokens: Token::for_client(connection, &uuid),
+            messages: messages_by(connection, &uuid),
+            reactions: reactions_by(connection, &uuid),
+            attachments: Attachment::for_owner(connection, &uuid),
+            profile: client,
+        }
+    }
+}
+
+// Oldest first, with their reactions and attachments
+fn messages_by(connection: &Connection, author_uuid: &str) -> Vec<Message> {
+    let query = "SELECT * FROM messages WHERE author_uuid = ? ORDER BY rowid";
+    let mut messages = connection
+        .prepare(query)
+        .unwrap()
+        .into_iter()
+        .bind((1, author_uuid))
+        .unwrap()
+        .map(|row| Message::from_db_row(row.unwrap()))
+        .collect::<Vec<_>>();
+    for batch in messages.chunks_mut(EXPORT_BATCH) {
+        let uuids = batch.iter().map(|m| m.get_uuid()).collect::<Vec<_>>();
+        let mut reactions = Reaction::for_messages(connection, &uuids);
+        let mut attachments = Attachment::for_messages(connection, &uuids);
+        for message in batch.iter_mut() {
+            message.reactions = reactions.remove(&message.get_uuid()).unwrap_or_default();
+            message.attachments = attachments.remove(&message.get_uuid()).unwrap_or_default();
+        }
+    }
+    messages
+}
+
+fn reactions_by(connection: &Connection, client_uuid: &str) -> Vec<OwnReaction> {
+    let query = "SELECT message_uuid, emoji, unix_time FROM reactions WHERE client_uuid = ? ORDER BY unix_time";
+    connection
+        .prepare(query)
+        .unwrap()
+        .into_iter()
+        .bind((1, client_uuid))
+        .unwrap()
+        .map(|row| {
+            let row = row.unwrap();
+            OwnReaction {
+                message_uuid: row.read::<&str, _>("message_uuid").into(),
+                emoji: row.read::<&str, _>("emoji").into(),
+                unix_time: row.read::<i64, _>("unix_time") as u64,
+            }
+        })
+        .collect()
+}
+
+fn execute(connection: &Connection, query: &str, uuid: &str) -> sqlite::Result<()> {
+    let mut statement = connection.prepare(query)?;
+    statement.bind((1, uuid))?;
+    statement.next()?;
+    Ok(())
+}
+
+// Removes the client and its tokens, reactions, names and unsent uploads.
+// Its messages are anonymized or removed as configured. Unused blobs are left
+// for the next garbage collection. Nothing is changed unless all of it succeeds.
+pub fn delete(connection: &Connection, client: &Client, messages: DeletedMessages) -> bool {
+    let deleted = connection
+        .execute("BEGIN")
+        .and_then(|_| delete_all(connection, client, messages))
+        .and_then(|_| connection.execute("COMMIT"));
+    match deleted {
+        Ok(()) => true,
+        Err(e) => {
+            error!(error = %e, client = %client.get_uuid(), "failed to delete account");
+            let _ = connection.execute("ROLLBACK");
+            false
+        }
+    }
+}
+
+fn delete_all(
+    connection: &Connection,
+    client: &Client,
+    messages: DeletedMessages,
+) -> sqlite::Result<()> {
+    let uuid = client.get_uuid();
+    if messages == DeletedMessages::Remove {
+        remove_messages(connection, &uuid)?;
+    }
+    for query in [
+        "UPDATE messages SET author_uuid = ?1 WHERE author_uuid = ?2",
+        "UPDATE attachments SET owner_uuid = ?1 WHERE owner_uuid = ?2 AND attachment_id IN (SELECT attachment_id FROM message_attachments)",
+    ] {
+        let mut statement = connection.prepare(query)?;
+        statement.bind_iter::<_, (_, Value)>([
+            (1, DELETED_USER_UUID.into()),
+            (2, uuid.as_ref().into()),
+        ])?;
+        statement.next()?;
+    }
+    for query in [
+        "DELETE FROM attachments WHERE owner_uuid = ?",
+        "DELETE FROM reactions WHERE client_uuid = ?",
+        "DELETE FROM tokens WHERE client_uuid = ?",
+        "DELETE FROM username_history WHERE client_uuid = ?",
+        "DELETE FROM registrations WHERE client_uuid = ?",
+        "DELETE FROM clients WHERE uuid = ?",
+    ] {
+        execute(connection, query, &uuid)?;
+    }
+    if let Some(avatar) = client.avatar.as_deref() {
+        Blob::release(connection, avatar)?;
+    }
+    Ok(())
+}
+
+// Unlinks their attachments and takes their replies out of the reply counts.
+// Replies and threads of others under the removed messages become top level messages.
+fn remove_messages(connection: &Connection, author_uuid: &str) -> sqlite::Result<()> {
+    let query = "SELECT a.blob_hash, a.thumbnail_hash FROM message_attachments m JOIN attachments a ON a.attachment_id = m.attachment_id JOIN messages ON messages.message_uuid = m.message_uuid WHERE messages.author_uuid = ?";
+    let mut linked = vec![];
+    for row in connection
+        .prepare(query)?
+        .into_iter()
+        .bind((1, author_uuid))?
+    {
+        let row = row?;
+        linked.push((
+            row.read::<&str, _>("blob_hash").to_string(),
+            row.read::<Option<&str>, _>("thumbnail_hash")
+                .map(String::from),
+        ));
+    }
+    for (blob_hash, thumbnail_hash) in linked {
+        Blob::release(connection, &blob_hash)?;
+        if let Some(thumbnail_hash) = thumbnail_hash {
+            Blob::release(connection, &thumbnail_hash)?;
+        }
+    }
+    let query = "SELECT parent_uuid, COUNT(*) AS replies FROM messages WHERE author_uuid = ? AND parent_uuid IS NOT NULL GROUP BY parent_uuid";
+    let mut replies = vec![];
+    for row in connection
+        .prepare(query)?
+        .into_iter()
+        .bind((1, author_uuid))?
+    {
+        let row = row?;
+        replies.push((
+            row.read::<&str, _>("parent_uuid").to_string(),
+            row.read::<i64, _>("replies"),
+        ));
+    }
+    for (parent_uuid, count) in replies {
+        let query =
+            "UPDATE messages SET reply_count = MAX(reply_count - ?, 0) WHERE message_uuid = ?";
+        let mut statement = connection.prepare(query)?;
+        statement.bind_iter::<_, (_, Value)>([(1, count.into()), (2, parent_uuid.into())])?;
+        statement.next()?;
+    }
+    for query in [
+        "UPDATE messages SET parent_uuid = NULL, in_thread = 0 WHERE author_uuid IS NOT ?1 AND parent_uuid IN (SELECT message_uuid FROM messages WHERE author_uuid = ?1)",
+        "DELETE FROM message_attachments WHERE message_uuid IN (SELECT message_uuid FROM messages WHERE author_uuid = ?)",
+        "DELETE FROM reactions WHERE message_uuid IN (SELECT message_uuid FROM messages WHERE author_uuid = ?)",
+        "DELETE FROM messages WHERE author_uuid = ?",
+    ] {
+        execute(connection, query, author_uuid)?;
+    }
+    Ok(())
+}
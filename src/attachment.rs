@@ -0,0 +1,228 @@
+// File Contains Structs for File Attachments and their Storage
+
+use std::{collections::HashMap, sync::Arc};
+
+use rand::distributions::{Alphanumeric, DistString};
+use serde::Serialize;
+use sqlite::{Connection, Row, Value};
+
+use crate::{blob::Blob, media::Upload, server::unix_time};
+
+pub const ATTACHMENTS_PER_MESSAGE: usize = 10;
+const FILE_NAME_LIMIT: usize = 128;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AttachmentError {
+    TooLarge,
+    QuotaExceeded,
+    Storage,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Attachment {
+    attachment_id: Arc<str>,
+    owner_uuid: Arc<str>,
+    pub file_name: String,
+    pub content_type: String,
+    pub size: u64,
+    unix_time: u64,
+    // Set for images so clients can lay them out before downloading
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    // Download urls use the attachment id so contents can move between blobs
+    #[serde(skip)]
+    blob_hash: Arc<str>,
+    #[serde(skip)]
+    thumbnail_hash: Option<Arc<str>>,
+}
+
+impl Attachment {
+    fn generate_attachment_id() -> Arc<str> {
+        Arc::from(Alphanumeric.sample_string(&mut rand::thread_rng(), 16))
+    }
+
+    pub fn new(
+        owner_uuid: Arc<str>,
+        file_name: &str,
+        upload: &Upload,
+        blob_hash: &str,
+        thumbnail_hash: Option<&str>,
+    ) -> Self {
+        let file_name = file_name
+            .chars()
+            .filter(|c| !c.is_control() && !matches!(c, '/' | '\\' | '"'))
+            .take(FILE_NAME_LIMIT)
+            .collect::<String>();
+        Self {
+            attachment_id: Self::generate_attachment_id(),
+            owner_uuid,
+            file_name: if file_name.is_empty() {
+                "attachment".to_string()
+            } else {
+                file_name
+            },
+            content_type: upload.content_type.clone(),
+            size: upload.data.len() as u64,
+            unix_time: unix_time(),
+            width: upload.image.as_ref().map(|image| image.width),
+            height: upload.image.as_ref().map(|image| image.height),
+            blob_hash: blob_hash.into(),
+            thumbnail_hash: thumbnail_hash.map(Arc::from),
+        }
+    }
+
+    pub fn from_db_row(row: Row) -> Self {
+        Self {
+            attachment_id: row.read::<&str, _>("attachment_id").into(),
+            owner_uuid: row.read::<&str, _>("owner_uuid").into(),
+            file_name: row.read::<&str, _>("file_name").into(),
+            content_type: row.read::<&str, _>("content_type").into(),
+            size: row.read::<i64, _>("size") as u64,
+            unix_time: row.read::<i64, _>("unix_time") as u64,
+            width: row.read::<Option<i64>, _>("width").map(|w| w as u32),
+            height: row.read::<Option<i64>, _>("height").map(|h| h as u32),
+            blob_hash: row
+                .read::<Option<&str>, _>("blob_hash")
+                .unwrap_or_default()
+                .into(),
+            thumbnail_hash: row.read::<Option<&str>, _>("thumbnail_hash").map(Arc::from),
+        }
+    }
+
+    pub fn write_to_db(&self, connection: &Connection) {
+        let query = "INSERT INTO attachments (attachment_id, owner_uuid, file_name, content_type, size, unix_time, width, height, blob_hash, thumbnail_hash) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+        let mut statement = connection.prepare(query).unwrap();
+        statement
+            .bind_iter::<_, (_, Value)>([
+                (1, self.attachment_id.as_ref().into()),
+                (2, self.owner_uuid.as_ref().into()),
+                (3, self.file_name.as_str().into()),
+                (4, self.content_type.as_str().into()),
+                (5, (self.size as i64).into()),
+                (6, (self.unix_time as i64).into()),
+                (7, self.width.map(|w| w as i64).into()),
+                (8, self.height.map(|h| h as i64).into()),
+                (9, self.blob_hash.as_ref().into()),
+                (10, self.thumbnail_hash.as_deref().into()),
+            ])
+            .unwrap();
+        let _ = statement.next();
+    }
+
+    pub fn regenerate_id(&mut self) {
+        self.attachment_id = Self::generate_attachment_id();
+    }
+
+    pub fn get_id(&self) -> Arc<str> {
+        self.attachment_id.clone()
+    }
+
+    pub fn get_owner_uuid(&self) -> Arc<str> {
+        self.owner_uuid.clone()
+    }
+
+    pub fn get_blob_hash(&self) -> Arc<str> {
+        self.blob_hash.clone()
+    }
+
+    pub fn get_thumbnail_hash(&self) -> Option<Arc<str>> {
+        self.thumbnail_hash.clone()
+    }
+
+    pub fn is_image(&self) -> bool {
+        self.width.is_some()
+    }
+
+    pub fn get(connection: &Connection, attachment_id: &str) -> Option<Self> {
+        let query = "SELECT * FROM attachments WHERE attachment_id = ?";
+        connection
+            .prepare(query)
+            .unwrap()
+            .into_iter()
+            .bind((1, attachment_id))
+            .unwrap()
+            .map(|row| Self::from_db_row(row.unwrap()))
+            .next()
+    }
+
+    // Oldest first, whether or not a message uses them
+    pub fn for_owner(connection: &Connection, owner_uuid: &str) -> Vec<Self> {
+        let query = "SELECT * FROM attachments WHERE owner_uuid = ? ORDER BY unix_time, rowid";
+        connection
+            .prepare(query)
+            .unwrap()
+            .into_iter()
+            .bind((1, owner_uuid))
+            .unwrap()
+            .map(|row| Self::from_db_row(row.unwrap()))
+            .collect()
+    }
+
+    // Total size of everything uploaded by a client
+    pub fn used_storage(connection: &Connection, owner_uuid: &str) -> u64 {
+        let query = "SELECT COALESCE(SUM(size), 0) AS used FROM attachments WHERE owner_uuid = ?";
+        connection
+            .prepare(query)
+            .unwrap()
+            .into_iter()
+            .bind((1, owner_uuid))
+            .unwrap()
+            .map(|row| row.unwrap().read::<i64, _>("used") as u64)
+            .next()
+            .unwrap_or(0)
+    }
+
+    pub fn link_to_message(&self, connection: &Connection, message_uuid: &str) {
+        let query = "INSERT INTO message_attachments (message_uuid, attachment_id) VALUES (?, ?)";
+        let mut statement = connection.prepare(query).unwrap();
+        statement
+            .bind_iter::<_, (_, Value)>([
+                (1, message_uuid.into()),
+                (2, self.attachment_id.as_ref().into()),
+            ])
+            .unwrap();
+        let _ = statement.next();
+        if connection.change_count() > 0 {
+            Blob::retain(connection, &self.blob_hash);
+            if let Some(thumbnail_hash) = self.thumbnail_hash.as_deref() {
+                Blob::retain(connection, thumbnail_hash);
+            }
+        }
+    }
+
+    // Attachments grouped by the messages referencing them, in upload order
+    pub fn for_messages(
+        connection: &Connection,
+        message_uuids: &[Arc<str>],
+    ) -> HashMap<Arc<str>, Vec<Self>> {
+        let mut attachments: HashMap<Arc<str>, Vec<Self>> = HashMap::new();
+        if message_uuids.is_empty() {
+            return attachments;
+        }
+        let query = format!(
+            "SELECT m.message_uuid, a.* FROM message_attachments m JOIN attachments a ON a.attachment_id = m.attachment_id WHERE m.message_uuid IN ({}) ORDER BY a.unix_time",
+            vec!["?"; message_uuids.len()].join(", ")
+        );
+        let rows = connection
+            .prepare(query)
+            .unwrap()
+            .into_iter()
+            .bind_iter::<_, (_, Value)>(
+                message_uuids
+                    .iter()
+                    .enumerate()
+                    .map(|(i, uuid)| (i + 1, uuid.as_ref().into())),
+            )
+            .unwrap();
+        for row in rows {
+            let row = row.unwrap();
+            attachments
+                .entry(row.read::<&str, _>("message_uuid").into())
+                .or_default()
+                .push(Self::from_db_row(row));
+        }
+        attachments
+    }
+}
@@ -0,0 +1,179 @@
+// File Contains the Append Only Log of Administrative and Security Events
+//
+// Entries live in the audit_log table, triggers refuse to change them or delete
+// any younger than MIN_RETENTION_DAYS.
+// The actor is the uuid of the client that acted, "cli" on the server host or
+// nobody for requests without a token. The target is what was acted on: a
+// client uuid, token id, guest id, invite id or sign up request id.
+
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader},
+    net::IpAddr,
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+use sqlite::{Connection, Row, Value};
+use tracing::{info, warn};
+
+use crate::server::unix_time;
+
+pub const CLI_ACTOR: &str = "cli";
+pub const DEFAULT_LIMIT: usize = 100;
+pub const LIMIT: usize = 500;
+// Matches the delete trigger in server.rs
+pub const MIN_RETENTION_DAYS: u64 = 30;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEntry {
+    pub entry_id: i64,
+    pub unix_time: u64,
+    pub event: String,
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    pub ip: Option<IpAddr>,
+    pub detail: String,
+}
+
+// Every field that is set has to match, newest entries first
+#[derive(Debug, Clone, Default)]
+pub struct AuditFilter {
+    pub event: Option<String>,
+    pub actor: Option<String>,
+    pub target: Option<String>,
+    pub ip: Option<IpAddr>,
+    pub since: Option<u64>,
+    pub until: Option<u64>,
+    // Entry id to continue below, from the last entry of the previous page
+    pub before: Option<i64>,
+    pub limit: usize,
+}
+
+// A line of the log file kept before the table existed
+#[derive(Deserialize)]
+struct FileEntry {
+    unix_time: u64,
+    event: String,
+    ip: Option<IpAddr>,
+    detail: String,
+}
+
+impl AuditEntry {
+    pub fn record(
+        connection: &Connection,
+        event: &str,
+        actor: Option<&str>,
+        target: Option<&str>,
+        ip: Option<IpAddr>,
+        detail: &str,
+    ) {
+        Self::insert(connection, unix_time(), event, actor, target, ip, detail);
+    }
+
+    fn insert(
+        connection: &Connection,
+        unix_time: u64,
+        event: &str,
+        actor: Option<&str>,
+        target: Option<&str>,
+        ip: Option<IpAddr>,
+        detail: &str,
+    ) {
+        let query = "INSERT INTO audit_log (unix_time, event, actor, target, ip, detail) VALUES (?, ?, ?, ?, ?, ?)";
+        let mut statement = connection.prepare(query).unwrap();
+        statement
+            .bind_iter::<_, (_, Value)>([
+                (1, (unix_time as i64).into()),
+                (2, event.into()),
+                (3, actor.into()),
+                (4, target.into()),
+                (5, ip.map(|ip| ip.to_string()).into()),
+                (6, detail.into()),
+            ])
+            .unwrap();
+        let _ = statement.next();
+    }
+
+    pub fn from_db_row(row: Row) -> Self {
+        Self {
+            entry_id: row.read::<i64, _>("entry_id"),
+            unix_time: row.read::<i64, _>("unix_time") as u64,
+            event: row.read::<&str, _>("event").into(),
+            actor: row.read::<Option<&str>, _>("actor").map(String::from),
+            target: row.read::<Option<&str>, _>("target").map(String::from),
+            ip: row
+                .read::<Option<&str>, _>("ip")
+                .and_then(|ip| ip.parse().ok()),
+            detail: row.read::<&str, _>("detail").into(),
+        }
+    }
+
+    pub fn query(connection: &Connection, filter: &AuditFilter) -> Vec<Self> {
+        let query = "SELECT * FROM audit_log WHERE (?1 IS NULL OR event = ?1) AND (?2 IS NULL OR actor = ?2) AND (?3 IS NULL OR target = ?3) AND (?4 IS NULL OR ip = ?4) AND (?5 IS NULL OR unix_time >= ?5) AND (?6 IS NULL OR unix_time <= ?6) AND (?7 IS NULL OR entry_id < ?7) ORDER BY entry_id DESC LIMIT ?8";
+        connection
+            .prepare(query)
+            .unwrap()
+            .into_iter()
+            .bind_iter::<_, (_, Value)>([
+                (1, filter.event.as_deref().into()),
+                (2, filter.actor.as_deref().into()),
+                (3, filter.target.as_deref().into()),
+                (4, filter.ip.map(|ip| ip.to_string()).into()),
+                (5, filter.since.map(|t| t as i64).into()),
+                (6, filter.until.map(|t| t as i64).into()),
+                (7, filter.before.into()),
+                (8, (filter.limit.min(LIMIT) as i64).into()),
+            ])
+            .unwrap()
+            .map(|row| Self::from_db_row(row.unwrap()))
+            .collect()
+    }
+}
+
+// Deletes the entries older than before, returns how many there were
+pub fn prune(connection: &Connection, before: u64) -> usize {
+    let mut statement = connection
+        .prepare("DELETE FROM audit_log WHERE unix_time < ?")
+        .unwrap();
+    statement.bind((1, before as i64)).unwrap();
+    match statement.next() {
+        Ok(_) => connection.change_count(),
+        Err(e) => {
+            warn!(error = %e, "failed to prune audit log");
+            0
+        }
+    }
+}
+
+// Moves the entries of the old log file into the table, the file is kept
+// next to it with ".imported" added to its name
+pub fn import_file(connection: &Connection, path: &Path) {
+    let Ok(file) = File::open(path) else {
+        return;
+    };
+    let mut imported = 0;
+    for line in BufReader::new(file).lines().map_while(Result::ok) {
+        match serde_json::from_str::<FileEntry>(&line) {
+            Ok(entry) => {
+                AuditEntry::insert(
+                    connection,
+                    entry.unix_time,
+                    &entry.event,
+                    None,
+                    None,
+                    entry.ip,
+                    &entry.detail,
+                );
+                imported += 1;
+            }
+            Err(e) => warn!(error = %e, "skipped malformed audit log line"),
+        }
+    }
+    let mut renamed = path.as_os_str().to_owned();
+    renamed.push(".imported");
+    if let Err(e) = fs::rename(path, &renamed) {
+        warn!(error = %e, "failed to rename imported audit log file");
+    }
+    info!(imported, "imported audit log file into the database");
+}
@@ -0,0 +1,73 @@
+// Short lived cache of validated tokens so HTTP requests skip the server round trip
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use crate::{client::Client, server::unix_time};
+
+pub struct AuthCache {
+    capacity: usize,
+    ttl: Duration,
+    entries: HashMap<String, (Client, Instant)>,
+}
+
+impl AuthCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            capacity,
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    pub fn get(&mut self, token: &str) -> Option<Client> {
+        match self.entries.get(token) {
+            Some((client, cached))
+                if cached.elapsed() < self.ttl
+                    && client
+                        .expires_at
+                        .is_none_or(|expires_at| unix_time() < expires_at) =>
+            {
+                Some(client.clone())
+            }
+            Some(_) => {
+                self.entries.remove(token);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&mut self, token: &str, client: Client) {
+        if self.entries.len() >= self.capacity {
+            let ttl = self.ttl;
+            self.entries.retain(|_, (_, cached)| cached.elapsed() < ttl);
+        }
+        if self.entries.len() >= self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|(_, (_, cached))| *cached)
+                .map(|(token, _)| token.clone());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.entries
+            .insert(token.to_string(), (client, Instant::now()));
+    }
+
+    // Drops every cached token of a deleted client
+    pub fn remove_client(&mut self, uuid: &str) {
+        self.entries
+            .retain(|_, (client, _)| client.get_uuid().as_ref() != uuid);
+    }
+
+    // Drops every cached token with the given id so revoking it takes effect at once
+    pub fn remove_token_id(&mut self, token_id: &str) {
+        self.entries
+            .retain(|_, (client, _)| client.token_id.as_deref() != Some(token_id));
+    }
+}
@@ -0,0 +1,117 @@
+// File Contains the Content Addressed Store Attachment Contents are Kept in
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+use sqlite::{Connection, Value};
+use tracing::warn;
+
+use crate::server::unix_time;
+
+// Uploads that were never sent in a message are kept this long before collection
+pub const PENDING_LIFETIME: u64 = 24 * 60 * 60;
+
+pub struct Blob;
+
+impl Blob {
+    pub fn hash(data: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(data))
+    }
+
+    // Blobs are spread over subdirectories by the first byte of their hash
+    pub fn path(dir: &Path, hash: &str) -> PathBuf {
+        dir.join(&hash[..2]).join(hash)
+    }
+
+    // Returns the hash of the contents, writing them only if no identical blob exists
+    pub fn store(connection: &Connection, dir: &Path, data: &[u8]) -> io::Result<String> {
+        let hash = Self::hash(data);
+        let path = Self::path(dir, &hash);
+        if !Self::exists(connection, &hash) || !path.exists() {
+            std::fs::create_dir_all(path.parent().unwrap())?;
+            // Write next to the final path first so a partial file is never served
+            let partial = path.with_extension("partial");
+            std::fs::write(&partial, data)?;
+            std::fs::rename(&partial, &path)?;
+        }
+        let query =
+            "INSERT OR IGNORE INTO blobs (hash, size, ref_count, unix_time) VALUES (?, ?, 0, ?)";
+        let mut statement = connection.prepare(query).unwrap();
+        statement
+            .bind_iter::<_, (_, Value)>([
+                (1, hash.as_str().into()),
+                (2, (data.len() as i64).into()),
+                (3, (unix_time() as i64).into()),
+            ])
+            .unwrap();
+        let _ = statement.next();
+        Ok(hash)
+    }
+
+    pub fn exists(connection: &Connection, hash: &str) -> bool {
+        let query = "SELECT hash FROM blobs WHERE hash = ?";
+        connection
+            .prepare(query)
+            .unwrap()
+            .into_iter()
+            .bind((1, hash))
+            .unwrap()
+            .next()
+            .is_some()
+    }
+
+    pub fn retain(connection: &Connection, hash: &str) {
+        let query = "UPDATE blobs SET ref_count = ref_count + 1 WHERE hash = ?";
+        let mut statement = connection.prepare(query).unwrap();
+        statement.bind((1, hash)).unwrap();
+        let _ = statement.next();
+    }
+
+    pub fn release(connection: &Connection, hash: &str) -> sqlite::Result<()> {
+        let query = "UPDATE blobs SET ref_count = MAX(ref_count - 1, 0) WHERE hash = ?";
+        let mut statement = connection.prepare(query)?;
+        statement.bind((1, hash))?;
+        statement.next()?;
+        Ok(())
+    }
+
+    // Drops expired uploads no message uses, then every blob nothing refers to any more.
+    // Returns the number of blobs removed.
+    pub fn collect_garbage(connection: &Connection, dir: &Path) -> usize {
+        let query = "DELETE FROM attachments WHERE unix_time < ? AND attachment_id NOT IN (SELECT attachment_id FROM message_attachments)";
+        let mut statement = connection.prepare(query).unwrap();
+        statement
+            .bind((1, unix_time().saturating_sub(PENDING_LIFETIME) as i64))
+            .unwrap();
+        let _ = statement.next();
+
+        let query = "SELECT hash FROM blobs WHERE ref_count = 0 AND hash NOT IN (SELECT blob_hash FROM attachments WHERE blob_hash IS NOT NULL UNION SELECT thumbnail_hash FROM attachments WHERE thumbnail_hash IS NOT NULL)";
+        let unused = connection
+            .prepare(query)
+            .unwrap()
+            .into_iter()
+            .map(|row| row.unwrap().read::<&str, _>("hash").to_string())
+            .collect::<Vec<_>>();
+        let mut removed = 0;
+        for hash in unused.iter() {
+            match std::fs::remove_file(Self::path(dir, hash)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => {
+                    warn!(%hash, error = %e, "failed to remove blob");
+                    continue;
+                }
+            }
+            let mut statement = connection
+                .prepare("DELETE FROM blobs WHERE hash = ?")
+                .unwrap();
+            statement.bind((1, hash.as_str())).unwrap();
+            let _ = statement.next();
+            removed += 1;
+        }
+        removed
+    }
+}
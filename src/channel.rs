@@ -1,46 +1,229 @@
-use std::{collections::HashMap, net::SocketAddr};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+};
 
 use tokio::sync::mpsc::{
     channel, unbounded_channel, Receiver, Sender, UnboundedReceiver, UnboundedSender,
 };
+use tracing::error;
 
-use crate::{client::Client, server::Tx};
+use crate::{
+    account::AccountExport,
+    attachment::{Attachment, AttachmentError},
+    audit::{AuditEntry, AuditFilter},
+    client::{Client, PastUsername, Presence, UsernameError},
+    guest::Guest,
+    info::ServerInfo,
+    invite::Invite,
+    lockout::AuthError,
+    media::Upload,
+    message::Message,
+    metrics::ServerStats,
+    reaction::Reaction,
+    registration::{DecideError, RegisterError, Registration, RegistrationStatus},
+    server::{ClientExport, Tx, WsAuthMethods},
+    token::{Scopes, Token},
+};
 // use futures_util::StreamExt;
 #[derive(Hash, PartialEq, Eq)]
 pub enum Clients {
     WebSocket,
     Http,
+    Metrics,
 }
 
 //Requests to Server
 pub enum ClientInteractions {
     WsSocket,
-    WsValidateClient(String),
-    WsClientConnected { addr: SocketAddr, client: Client },
-    WsSetClientConnectedTx { addr: SocketAddr, tx: Tx },
+    WsAuthMethods,
+    WsValidateClient {
+        ip: IpAddr,
+        token: String,
+    },
+    WsClientConnected {
+        addr: SocketAddr,
+        client: Client,
+    },
+    WsSetClientConnectedTx {
+        addr: SocketAddr,
+        tx: Tx,
+    },
     WsGetConnectedClients,
-    WsClientLeft { addr: SocketAddr },
+    WsClientLeft {
+        addr: SocketAddr,
+    },
+    WsSetPresence {
+        addr: SocketAddr,
+        presence: Presence,
+        status_text: Option<String>,
+    },
+    WsStoreMessage {
+        message: Message,
+        attachments: Vec<String>,
+    },
+    WsReact {
+        client_uuid: Arc<str>,
+        message_uuid: String,
+        emoji: String,
+        added: bool,
+    },
 
     HttpSocket,
-    HttpValidateClient(String),
+    HttpValidateClient {
+        ip: IpAddr,
+        token: String,
+    },
     HttpGetConnectedClients,
     HttpGetAllClients,
+    HttpGetHistory {
+        thread: Option<String>,
+        before: Option<String>,
+        limit: usize,
+    },
+    HttpUploadLimit,
+    HttpCheckDatabase,
+    HttpServerInfo,
+    HttpStoreAttachment {
+        owner_uuid: Arc<str>,
+        file_name: String,
+        upload: Upload,
+    },
+    HttpGetAttachment {
+        attachment_id: String,
+        thumbnail: bool,
+    },
+    HttpSetAvatar {
+        uuid: Arc<str>,
+        avatar: Option<Vec<u8>>,
+    },
+    HttpGetAvatar(String),
+    HttpRenameClient {
+        uuid: Arc<str>,
+        username: String,
+        ip: IpAddr,
+    },
+    HttpGetUsernameHistory(String),
+    HttpFindClient(String),
+    HttpExportAccount(Arc<str>),
+    HttpDeleteAccount {
+        uuid: Arc<str>,
+        ip: IpAddr,
+    },
+    HttpGetTokens(Arc<str>),
+    HttpCreateToken {
+        client_uuid: Arc<str>,
+        name: String,
+        scopes: Scopes,
+        expires_at: Option<u64>,
+        ip: IpAddr,
+    },
+    HttpRevokeToken {
+        client_uuid: Arc<str>,
+        token_id: String,
+        ip: IpAddr,
+    },
+    HttpCreateInvite {
+        created_by: Arc<str>,
+        max_uses: u32,
+        expires_at: Option<u64>,
+        ip: IpAddr,
+    },
+    HttpGetInvites,
+    HttpRevokeInvite {
+        invite_id: String,
+        revoked_by: Arc<str>,
+        ip: IpAddr,
+    },
+    HttpCreateGuest {
+        created_by: Arc<str>,
+        username: String,
+        expires_at: u64,
+        ip: IpAddr,
+    },
+    HttpGetGuests,
+    HttpRevokeGuest {
+        guest_id: String,
+        revoked_by: Arc<str>,
+        ip: IpAddr,
+    },
+    HttpRegister {
+        ip: IpAddr,
+        code: String,
+        username: String,
+    },
+    HttpRequestRegistration {
+        ip: IpAddr,
+        username: String,
+    },
+    HttpGetRegistrations(Option<RegistrationStatus>),
+    HttpDecideRegistration {
+        request_id: String,
+        approve: bool,
+        decided_by: Arc<str>,
+        ip: IpAddr,
+    },
+    HttpClaimRegistration {
+        ip: IpAddr,
+        claim_token: String,
+    },
+    HttpGetAuditLog(AuditFilter),
+
+    MetricsSocket,
+    MetricsServerStats,
 }
 
 // Responses from Server
 #[derive(Debug, Clone)]
 pub enum ServerInteractions {
     WsSocket(SocketAddr),
-    WsValidateClient(Option<Client>),
+    WsAuthMethods(WsAuthMethods),
+    WsValidateClient(Result<Client, AuthError>),
     WsClientConnected,
     WsSetClientConnectedTx,
     WsGetConnectedClients(HashMap<SocketAddr, Client>),
     WsClientLeft,
+    WsSetPresence(Option<Client>),
+    WsStoreMessage(Option<Message>),
+    WsReact(Option<Reaction>),
 
     HttpSocket(SocketAddr),
-    HttpValidateClient(bool),
+    HttpValidateClient(Result<Client, AuthError>),
     HttpGetConnectedClients(HashMap<SocketAddr, Client>),
     HttpGetAllClients(Vec<Client>),
+    HttpGetHistory(Vec<Message>),
+    HttpUploadLimit(u64),
+    HttpCheckDatabase(bool),
+    HttpServerInfo(ServerInfo),
+    HttpStoreAttachment(Result<Attachment, AttachmentError>),
+    HttpGetAttachment(Option<(Attachment, PathBuf)>),
+    HttpSetAvatar(Option<Client>),
+    HttpGetAvatar(Option<(Arc<str>, PathBuf)>),
+    HttpRenameClient(Result<Client, UsernameError>),
+    HttpGetUsernameHistory(Vec<PastUsername>),
+    HttpFindClient(Option<Client>),
+    HttpExportAccount(Option<AccountExport>),
+    HttpDeleteAccount(bool),
+    HttpGetTokens(Vec<Token>),
+    HttpCreateToken(Option<(Token, Arc<str>)>),
+    HttpRevokeToken(bool),
+    HttpCreateInvite((Invite, Arc<str>)),
+    HttpGetInvites(Vec<Invite>),
+    HttpRevokeInvite(bool),
+    HttpCreateGuest(Result<(Guest, ClientExport), UsernameError>),
+    HttpGetGuests(Vec<Guest>),
+    HttpRevokeGuest(bool),
+    HttpRegister(Result<ClientExport, RegisterError>),
+    HttpRequestRegistration(Result<(Registration, Arc<str>), RegisterError>),
+    HttpGetRegistrations(Vec<Registration>),
+    HttpDecideRegistration(Result<Registration, DecideError>),
+    HttpClaimRegistration(Result<ClientExport, RegisterError>),
+    HttpGetAuditLog(Vec<AuditEntry>),
+
+    MetricsSocket(Option<SocketAddr>),
+    MetricsServerStats(ServerStats),
 }
 
 impl ServerInteractions {
@@ -48,15 +231,49 @@ impl ServerInteractions {
         match self {
             Self::WsSocket(addr) => Some(*addr),
             Self::HttpSocket(addr) => Some(*addr),
+            Self::MetricsSocket(addr) => *addr,
             _ => None,
         }
     }
-    pub fn client_validation(&self) -> Option<Client> {
+    pub fn auth_methods(&self) -> WsAuthMethods {
+        match self {
+            Self::WsAuthMethods(methods) => *methods,
+            _ => WsAuthMethods::default(),
+        }
+    }
+    pub fn client_validation(&self) -> Result<Client, AuthError> {
         match self {
             Self::WsValidateClient(client) => client.clone(),
+            Self::HttpValidateClient(client) => client.clone(),
+            _ => Err(AuthError::Invalid),
+        }
+    }
+    pub fn updated_client(&self) -> Option<Client> {
+        match self {
+            Self::WsSetPresence(client)
+            | Self::HttpSetAvatar(client)
+            | Self::HttpFindClient(client) => client.clone(),
+            _ => None,
+        }
+    }
+    pub fn renamed_client(&self) -> Result<Client, UsernameError> {
+        match self {
+            Self::HttpRenameClient(client) => client.clone(),
+            _ => Err(UsernameError::Invalid),
+        }
+    }
+    pub fn account_export(&self) -> Option<AccountExport> {
+        match self {
+            Self::HttpExportAccount(export) => export.clone(),
             _ => None,
         }
     }
+    pub fn username_history(&self) -> Vec<PastUsername> {
+        match self {
+            Self::HttpGetUsernameHistory(history) => history.to_owned(),
+            _ => vec![],
+        }
+    }
     pub fn connected_clients(&self) -> Option<HashMap<SocketAddr, Client>> {
         match self {
             Self::WsGetConnectedClients(map) => Some(map.clone()),
@@ -70,12 +287,143 @@ impl ServerInteractions {
             _ => vec![],
         }
     }
-    pub fn get_bool(&self) -> bool {
+    pub fn stored_message(&self) -> Option<Message> {
+        match self {
+            Self::WsStoreMessage(message) => message.clone(),
+            _ => None,
+        }
+    }
+    pub fn reaction(&self) -> Option<Reaction> {
+        match self {
+            Self::WsReact(reaction) => reaction.clone(),
+            _ => None,
+        }
+    }
+    pub fn history(&self) -> Vec<Message> {
+        match self {
+            Self::HttpGetHistory(messages) => messages.to_owned(),
+            _ => vec![],
+        }
+    }
+    pub fn upload_limit(&self) -> u64 {
+        match self {
+            Self::HttpUploadLimit(limit) => *limit,
+            _ => 0,
+        }
+    }
+    pub fn stored_attachment(&self) -> Result<Attachment, AttachmentError> {
+        match self {
+            Self::HttpStoreAttachment(attachment) => attachment.clone(),
+            _ => Err(AttachmentError::Storage),
+        }
+    }
+    pub fn attachment(&self) -> Option<(Attachment, PathBuf)> {
+        match self {
+            Self::HttpGetAttachment(attachment) => attachment.clone(),
+            _ => None,
+        }
+    }
+    pub fn avatar(&self) -> Option<(Arc<str>, PathBuf)> {
+        match self {
+            Self::HttpGetAvatar(avatar) => avatar.clone(),
+            _ => None,
+        }
+    }
+    pub fn tokens(&self) -> Vec<Token> {
+        match self {
+            Self::HttpGetTokens(tokens) => tokens.to_owned(),
+            _ => vec![],
+        }
+    }
+    pub fn created_token(&self) -> Option<(Token, Arc<str>)> {
+        match self {
+            Self::HttpCreateToken(token) => token.clone(),
+            _ => None,
+        }
+    }
+    pub fn revoked(&self) -> bool {
+        matches!(
+            self,
+            Self::HttpRevokeToken(true)
+                | Self::HttpRevokeInvite(true)
+                | Self::HttpRevokeGuest(true)
+        )
+    }
+    pub fn deleted(&self) -> bool {
+        matches!(self, Self::HttpDeleteAccount(true))
+    }
+    pub fn created_invite(&self) -> Option<(Invite, Arc<str>)> {
+        match self {
+            Self::HttpCreateInvite(invite) => Some(invite.clone()),
+            _ => None,
+        }
+    }
+    pub fn invites(&self) -> Vec<Invite> {
+        match self {
+            Self::HttpGetInvites(invites) => invites.to_owned(),
+            _ => vec![],
+        }
+    }
+    pub fn created_guest(&self) -> Result<(Guest, ClientExport), UsernameError> {
+        match self {
+            Self::HttpCreateGuest(guest) => guest.clone(),
+            _ => Err(UsernameError::Invalid),
+        }
+    }
+    pub fn guests(&self) -> Vec<Guest> {
+        match self {
+            Self::HttpGetGuests(guests) => guests.to_owned(),
+            _ => vec![],
+        }
+    }
+    pub fn registration(&self) -> Result<ClientExport, RegisterError> {
         match self {
-            Self::HttpValidateClient(value) => *value,
+            Self::HttpRegister(export) | Self::HttpClaimRegistration(export) => export.clone(),
+            _ => Err(RegisterError::InvalidInvite),
+        }
+    }
+    pub fn requested_registration(&self) -> Result<(Registration, Arc<str>), RegisterError> {
+        match self {
+            Self::HttpRequestRegistration(registration) => registration.clone(),
+            _ => Err(RegisterError::Closed),
+        }
+    }
+    pub fn registrations(&self) -> Vec<Registration> {
+        match self {
+            Self::HttpGetRegistrations(registrations) => registrations.to_owned(),
+            _ => vec![],
+        }
+    }
+    pub fn decided_registration(&self) -> Result<Registration, DecideError> {
+        match self {
+            Self::HttpDecideRegistration(registration) => registration.clone(),
+            _ => Err(DecideError::Unknown),
+        }
+    }
+    pub fn audit_entries(&self) -> Vec<AuditEntry> {
+        match self {
+            Self::HttpGetAuditLog(entries) => entries.to_owned(),
+            _ => vec![],
+        }
+    }
+    pub fn database_reachable(&self) -> bool {
+        match self {
+            Self::HttpCheckDatabase(reachable) => *reachable,
             _ => false,
         }
     }
+    pub fn server_info(&self) -> Option<ServerInfo> {
+        match self {
+            Self::HttpServerInfo(info) => Some(info.to_owned()),
+            _ => None,
+        }
+    }
+    pub fn server_stats(&self) -> ServerStats {
+        match self {
+            Self::MetricsServerStats(stats) => *stats,
+            _ => ServerStats::default(),
+        }
+    }
 }
 
 pub struct ServerChannel {
@@ -94,7 +442,7 @@ impl ClientChannel {
             .send
             .send(req)
             .await
-            .map_err(|e| eprintln!("Failed to Send message to server : {e}"));
+            .map_err(|e| error!(error = %e, "failed to send message to server"));
         self.recieve.recv().await.unwrap()
     }
 }
@@ -1,89 +1,360 @@
 //File Contains Structs for Client Represententaion and Manipulation
 
-use rand::distributions::{Alphanumeric, DistString};
-use serde::Serialize;
+use rand::{rngs::OsRng, RngCore};
+use serde::{Deserialize, Serialize};
 use sqlite::{Connection, Row, Value};
 use std::sync::Arc;
 use derivative::Derivative;
-use crate::server::Tx;
+use crate::{
+    server::{unix_time, Tx},
+    token::Scopes,
+};
+
+pub const USERNAME_MIN: usize = 2;
+pub const USERNAME_LIMIT: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsernameError {
+    Invalid,
+    // In use by another client, now or before a rename
+    Taken,
+}
+
+// A name the client went by before renaming
+#[derive(Debug, Clone, Serialize)]
+pub struct PastUsername {
+    pub username: String,
+    pub changed_at: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Presence {
+    #[default]
+    Online,
+    Idle,
+    DoNotDisturb,
+    Invisible,
+    Offline,
+}
+
+impl Presence {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Online => "online",
+            Self::Idle => "idle",
+            Self::DoNotDisturb => "do_not_disturb",
+            Self::Invisible => "invisible",
+            Self::Offline => "offline",
+        }
+    }
+
+    pub fn from_db(value: &str) -> Self {
+        match value {
+            "idle" => Self::Idle,
+            "do_not_disturb" => Self::DoNotDisturb,
+            "invisible" => Self::Invisible,
+            _ => Self::Online,
+        }
+    }
+
+    // Presence as seen by everyone but the client itself
+    pub fn visible(&self) -> Self {
+        match self {
+            Self::Invisible => Self::Offline,
+            p => *p,
+        }
+    }
+}
+
 #[derive(Derivative, Serialize)]
 #[derivative(Debug, Clone,Hash, PartialEq, Eq)]
 pub struct Client {
     uuid: Arc<str>,
-    #[serde(skip)]
-    token: Arc<str>,
 
     pub username: String,
     pub display_name: String,
     pub about_me: String,
+    pub presence: Presence,
+    pub status_text: Option<String>,
+    pub last_seen: u64,
+    // Hash of the avatar contents, changes whenever the picture does
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub avatar: Option<Arc<str>>,
+    // Token the session authenticated with and what it may do
+    #[derivative(PartialEq="ignore")]
+    #[derivative(Hash="ignore")]
+    #[serde(skip)]
+    pub token_id: Option<Arc<str>>,
+    #[derivative(PartialEq="ignore")]
+    #[derivative(Hash="ignore")]
+    #[serde(skip)]
+    pub scopes: Scopes,
     #[derivative(PartialEq="ignore")]
     #[derivative(Hash="ignore")]
     #[serde(skip)]
     pub tx: Option<Tx>,
+    // Temporary account, see guest.rs
+    pub is_guest: bool,
+    // When the token of the session expires and its sessions are closed
+    #[derivative(PartialEq="ignore")]
+    #[derivative(Hash="ignore")]
+    #[serde(skip)]
+    pub expires_at: Option<u64>,
 }
 
 impl Client {
     // Add new Client
-    fn generate_token() -> Arc<str> {
-        let s = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
-        Arc::from(s.as_str())
+    // Random (version 4) uuid, "xxxxxxxx-xxxx-4xxx-yxxx-xxxxxxxxxxxx" in lowercase hex.
+    // Clients created before have "xxx-xxx-xxx-xxx-" with alphanumerics.
+    fn random_uuid() -> Arc<str> {
+        let mut bytes = [0u8; 16];
+        OsRng.fill_bytes(&mut bytes);
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        let hex = bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        Arc::from(format!(
+            "{}-{}-{}-{}-{}",
+            &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..]
+        ))
     }
 
-    fn generate_uuid() -> Arc<str> {
-        let mut s = "".to_string();
-        for _ in 0..4 {
-            s.push_str(&Alphanumeric.sample_string(&mut rand::thread_rng(), 3));
-            s.push('-')
+    // Unused by clients and guests
+    pub fn generate_uuid(connection: &Connection) -> Arc<str> {
+        let mut uuid = Self::random_uuid();
+        while Self::is_taken(connection, &uuid) {
+            uuid = Self::random_uuid();
         }
-        Arc::from(s.as_str())
+        uuid
+    }
+
+    fn is_taken(connection: &Connection, uuid: &str) -> bool {
+        connection
+            .prepare("SELECT 1 FROM clients WHERE uuid = ?1 UNION ALL SELECT 1 FROM guests WHERE uuid = ?1")
+            .unwrap()
+            .into_iter()
+            .bind((1, uuid))
+            .unwrap()
+            .next()
+            .is_some()
+    }
+
+    // ASCII letters, digits, '_', '-' and '.', so names compare case-insensitively in sqlite
+    pub fn is_valid_username(username: &str) -> bool {
+        (USERNAME_MIN..=USERNAME_LIMIT).contains(&username.len())
+            && username
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    }
+
+    // Current and past names of every other client count, so old names keep resolving,
+    // as do the names of guests until they expire
+    pub fn is_username_taken(connection: &Connection, username: &str, uuid: Option<&str>) -> bool {
+        let query = "SELECT 1 FROM clients WHERE username = ?1 COLLATE NOCASE AND uuid IS NOT ?2 UNION ALL SELECT 1 FROM username_history WHERE username = ?1 AND client_uuid IS NOT ?2 UNION ALL SELECT 1 FROM guests WHERE username = ?1 COLLATE NOCASE AND expires_at > ?3";
+        connection
+            .prepare(query)
+            .unwrap()
+            .into_iter()
+            .bind_iter::<_, (_, Value)>([
+                (1, username.into()),
+                (2, uuid.into()),
+                (3, (unix_time() as i64).into()),
+            ])
+            .unwrap()
+            .next()
+            .is_some()
+    }
+
+    fn check_username(
+        connection: &Connection,
+        username: &str,
+        uuid: Option<&str>,
+    ) -> Result<(), UsernameError> {
+        if !Self::is_valid_username(username) {
+            return Err(UsernameError::Invalid);
+        }
+        if Self::is_username_taken(connection, username, uuid) {
+            return Err(UsernameError::Taken);
+        }
+        Ok(())
     }
 
     pub fn from_db_row(row: Row) -> Self {
         Self {
             uuid: row.read::<&str, _>("uuid").into(),
-            token: row.read::<&str, _>("token").into(),
             username: row.read::<&str, _>("username").into(),
             display_name: row.read::<&str, _>("display_name").into(),
             about_me: row.read::<&str, _>("about_me").into(),
+            presence: Presence::from_db(row.read::<&str, _>("presence")),
+            status_text: row.read::<Option<&str>, _>("status_text").map(String::from),
+            last_seen: row.read::<i64, _>("last_seen") as u64,
+            avatar: row.read::<Option<&str>, _>("avatar_hash").map(Arc::from),
+            token_id: None,
+            scopes: Scopes::default(),
             tx: None,
+            is_guest: false,
+            expires_at: None,
         }
     }
 
-    pub fn new(username: &str, connection: &Connection) -> Self {
+    pub fn new(username: &str, connection: &Connection) -> Result<Self, UsernameError> {
+        Self::check_username(connection, username, None)?;
         let s = Self {
-            uuid: Self::generate_uuid(),
-            token: Self::generate_token(),
+            uuid: Self::generate_uuid(connection),
             username: username.to_string(),
             display_name: username.to_string(),
             about_me: String::new(),
+            presence: Presence::Online,
+            status_text: None,
+            last_seen: 0,
+            avatar: None,
+            token_id: None,
+            scopes: Scopes::default(),
             tx: None,
+            is_guest: false,
+            expires_at: None,
         };
         s.write_to_db(connection);
-        s
+        Ok(s)
+    }
+
+    // The client currently going by the name, or else the latest one that went by it
+    pub fn find_by_username(connection: &Connection, username: &str) -> Option<Self> {
+        let query = "SELECT clients.* FROM clients LEFT JOIN username_history ON username_history.client_uuid = clients.uuid AND username_history.username = ?1 WHERE clients.username = ?1 COLLATE NOCASE OR username_history.username IS NOT NULL ORDER BY clients.username = ?1 COLLATE NOCASE DESC, username_history.changed_at DESC LIMIT 1";
+        connection
+            .prepare(query)
+            .unwrap()
+            .into_iter()
+            .bind((1, username))
+            .unwrap()
+            .map(|row| Self::from_db_row(row.unwrap()))
+            .next()
     }
 
-    pub fn regenerate_token(&mut self) {
-        self.token = Self::generate_token();
+    // Keeps the old name in the history of the client
+    pub fn rename(&mut self, connection: &Connection, username: &str) -> Result<(), UsernameError> {
+        if username == self.username {
+            return Ok(());
+        }
+        Self::check_username(connection, username, Some(&self.uuid))?;
+        self.write_username_to_db(connection, username);
+        Ok(())
+    }
+
+    // Renames without checking the name, the old one goes into the history
+    pub fn write_username_to_db(&mut self, connection: &Connection, username: &str) {
+        let query = "INSERT OR REPLACE INTO username_history (client_uuid, username, changed_at) VALUES (?, ?, ?)";
+        let mut statement = connection.prepare(query).unwrap();
+        statement
+            .bind_iter::<_, (_, Value)>([
+                (1, self.uuid.as_ref().into()),
+                (2, self.username.as_str().into()),
+                (3, (unix_time() as i64).into()),
+            ])
+            .unwrap();
+        let _ = statement.next();
+        let mut statement = connection
+            .prepare("UPDATE clients SET username = ? WHERE uuid = ?")
+            .unwrap();
+        statement
+            .bind_iter::<_, (_, Value)>([
+                (1, username.into()),
+                (2, self.uuid.as_ref().into()),
+            ])
+            .unwrap();
+        let _ = statement.next();
+        self.username = username.to_string();
     }
 
-    pub fn get_token(&self) -> Arc<str> {
-        self.token.clone()
+    // Newest first
+    pub fn username_history(connection: &Connection, uuid: &str) -> Vec<PastUsername> {
+        let query = "SELECT username, changed_at FROM username_history WHERE client_uuid = ? ORDER BY changed_at DESC, rowid DESC";
+        connection
+            .prepare(query)
+            .unwrap()
+            .into_iter()
+            .bind((1, uuid))
+            .unwrap()
+            .map(|row| {
+                let row = row.unwrap();
+                PastUsername {
+                    username: row.read::<&str, _>("username").into(),
+                    changed_at: row.read::<i64, _>("changed_at") as u64,
+                }
+            })
+            .collect()
     }
+
+    // Never written to the clients table
+    pub fn guest(uuid: Arc<str>, username: &str, expires_at: u64) -> Self {
+        Self {
+            uuid,
+            username: username.to_string(),
+            display_name: username.to_string(),
+            about_me: String::new(),
+            presence: Presence::Online,
+            status_text: None,
+            last_seen: 0,
+            avatar: None,
+            token_id: None,
+            scopes: Scopes::default(),
+            tx: None,
+            is_guest: true,
+            expires_at: Some(expires_at),
+        }
+    }
+
     pub fn get_uuid(&self) -> Arc<str> {
         self.uuid.clone()
     }
 
+    // Copy of the client as listed to others while it is not connected
+    pub fn offline(&self) -> Self {
+        let mut client = self.clone();
+        // An invisible client's status would give away that it is around
+        if client.presence == Presence::Invisible {
+            client.status_text = None;
+        }
+        client.presence = Presence::Offline;
+        client.tx = None;
+        client
+    }
+
     pub fn write_to_db(&self, connection: &Connection) {
-        //"CREATE TABLE clients (uuid TEXT, token TEXT, username TEXT, display_name TEXT, about_me TEXT);";
-        let query = "INSERT INTO clients VALUES (?, ?, ?, ?, ?)";
+        let query = "INSERT INTO clients (uuid, username, display_name, about_me) VALUES (?, ?, ?, ?)";
         let mut statement = connection.prepare(query).unwrap();
         statement
             .bind_iter::<_, (_, Value)>([
                 (1, self.uuid.to_string().as_str().into()),
-                (2, self.token.to_string().as_str().into()),
-                (3, self.username.clone().into()),
-                (4, self.display_name.clone().into()),
-                (5, self.about_me.clone().into()),
+                (2, self.username.clone().into()),
+                (3, self.display_name.clone().into()),
+                (4, self.about_me.clone().into()),
+            ])
+            .unwrap();
+        let _ = statement.next();
+    }
+
+    pub fn write_presence_to_db(&self, connection: &Connection) {
+        let query = "UPDATE clients SET presence = ?, status_text = ?, last_seen = ? WHERE uuid = ?";
+        let mut statement = connection.prepare(query).unwrap();
+        statement
+            .bind_iter::<_, (_, Value)>([
+                (1, self.presence.as_str().into()),
+                (2, self.status_text.clone().into()),
+                (3, (self.last_seen as i64).into()),
+                (4, self.uuid.to_string().as_str().into()),
+            ])
+            .unwrap();
+        let _ = statement.next();
+    }
+
+    pub fn write_avatar_to_db(&self, connection: &Connection) {
+        let query = "UPDATE clients SET avatar_hash = ? WHERE uuid = ?";
+        let mut statement = connection.prepare(query).unwrap();
+        statement
+            .bind_iter::<_, (_, Value)>([
+                (1, self.avatar.as_deref().into()),
+                (2, self.uuid.to_string().as_str().into()),
             ])
             .unwrap();
         let _ = statement.next();
@@ -0,0 +1,167 @@
+// File Contains Temporary Guest Accounts
+//
+// Guests live in their own table instead of clients and are purged once expired.
+// Their token ("tensorguest_<guest id>.<secret>") can read and send but manages
+// nothing, and their sessions are closed when it expires.
+
+use std::sync::Arc;
+
+use serde::Serialize;
+use sqlite::{Connection, Row, Value};
+
+use crate::{
+    client::{Client, UsernameError},
+    server::unix_time,
+    token::{self, Scopes, Token, TokenKey},
+};
+
+pub const GUEST_PREFIX: &str = "tensorguest";
+// Used when an admin does not say how long a guest stays
+pub const DEFAULT_LIFETIME: u64 = 24 * 60 * 60;
+pub const MAX_LIFETIME: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Guest {
+    guest_id: Arc<str>,
+    uuid: Arc<str>,
+    #[serde(skip)]
+    token_hash: Arc<str>,
+    pub username: String,
+    pub created_by: Arc<str>,
+    pub expires_at: u64,
+    pub created_at: u64,
+}
+
+impl Guest {
+    // The token is only ever known here, only its hash is stored
+    pub fn new(
+        connection: &Connection,
+        key: &TokenKey,
+        username: &str,
+        created_by: Arc<str>,
+        expires_at: u64,
+    ) -> Result<(Self, Arc<str>), UsernameError> {
+        if !Client::is_valid_username(username) {
+            return Err(UsernameError::Invalid);
+        }
+        if Client::is_username_taken(connection, username, None) {
+            return Err(UsernameError::Taken);
+        }
+        let mut guest_id = token::generate_token_id();
+        while Self::get(connection, &guest_id).is_some()
+            || Token::get(connection, &guest_id).is_some()
+        {
+            guest_id = token::generate_token_id();
+        }
+        let token = token::generate(Some(GUEST_PREFIX), &guest_id);
+        let s = Self {
+            guest_id,
+            uuid: Client::generate_uuid(connection),
+            token_hash: key.hash(&token).into(),
+            username: username.to_string(),
+            created_by,
+            expires_at,
+            created_at: unix_time(),
+        };
+        s.write_to_db(connection);
+        Ok((s, token))
+    }
+
+    pub fn from_db_row(row: Row) -> Self {
+        Self {
+            guest_id: row.read::<&str, _>("guest_id").into(),
+            uuid: row.read::<&str, _>("uuid").into(),
+            token_hash: row.read::<&str, _>("token_hash").into(),
+            username: row.read::<&str, _>("username").into(),
+            created_by: row.read::<&str, _>("created_by").into(),
+            expires_at: row.read::<i64, _>("expires_at") as u64,
+            created_at: row.read::<i64, _>("created_at") as u64,
+        }
+    }
+
+    pub fn write_to_db(&self, connection: &Connection) {
+        let query = "INSERT INTO guests (guest_id, uuid, token_hash, username, created_by, expires_at, created_at) VALUES (?, ?, ?, ?, ?, ?, ?)";
+        let mut statement = connection.prepare(query).unwrap();
+        statement
+            .bind_iter::<_, (_, Value)>([
+                (1, self.guest_id.as_ref().into()),
+                (2, self.uuid.as_ref().into()),
+                (3, self.token_hash.as_ref().into()),
+                (4, self.username.as_str().into()),
+                (5, self.created_by.as_ref().into()),
+                (6, (self.expires_at as i64).into()),
+                (7, (self.created_at as i64).into()),
+            ])
+            .unwrap();
+        let _ = statement.next();
+    }
+
+    pub fn get(connection: &Connection, guest_id: &str) -> Option<Self> {
+        let query = "SELECT * FROM guests WHERE guest_id = ?";
+        connection
+            .prepare(query)
+            .unwrap()
+            .into_iter()
+            .bind((1, guest_id))
+            .unwrap()
+            .map(|row| Self::from_db_row(row.unwrap()))
+            .next()
+    }
+
+    // Guests that have not expired yet, oldest first
+    pub fn all(connection: &Connection) -> Vec<Self> {
+        let query = "SELECT * FROM guests WHERE expires_at > ? ORDER BY created_at, rowid";
+        connection
+            .prepare(query)
+            .unwrap()
+            .into_iter()
+            .bind((1, unix_time() as i64))
+            .unwrap()
+            .map(|row| Self::from_db_row(row.unwrap()))
+            .collect()
+    }
+
+    // Returns whether the guest existed
+    pub fn revoke(connection: &Connection, guest_id: &str) -> bool {
+        let mut statement = connection
+            .prepare("DELETE FROM guests WHERE guest_id = ?")
+            .unwrap();
+        statement.bind((1, guest_id)).unwrap();
+        let _ = statement.next();
+        connection.change_count() > 0
+    }
+
+    // Returns the number of guests removed
+    pub fn remove_expired(connection: &Connection) -> usize {
+        let mut statement = connection
+            .prepare("DELETE FROM guests WHERE expires_at <= ?")
+            .unwrap();
+        statement.bind((1, unix_time() as i64)).unwrap();
+        let _ = statement.next();
+        connection.change_count()
+    }
+
+    pub fn get_id(&self) -> Arc<str> {
+        self.guest_id.clone()
+    }
+
+    pub fn get_uuid(&self) -> Arc<str> {
+        self.uuid.clone()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        unix_time() >= self.expires_at
+    }
+
+    pub fn verify(&self, token: &str, key: &TokenKey) -> bool {
+        key.verify(token, &self.token_hash)
+    }
+
+    // The guest as a connected client, with the scopes of its token
+    pub fn to_client(&self) -> Client {
+        let mut client = Client::guest(self.uuid.clone(), &self.username, self.expires_at);
+        client.token_id = Some(self.guest_id.clone());
+        client.scopes = Scopes::CLIENT;
+        client
+    }
+}
@@ -0,0 +1,55 @@
+// File Contains the State Behind the Health and Readiness Endpoints
+//
+// /healthz answers whenever the HTTP listener does. /readyz also wants both
+// listeners bound, the database answering and the server not draining, which it
+// does for `drain_secs` after being asked to stop so traffic can move elsewhere.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::Serialize;
+
+static WEBSOCKET_BOUND: AtomicBool = AtomicBool::new(false);
+static HTTP_BOUND: AtomicBool = AtomicBool::new(false);
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy)]
+pub enum Listener {
+    WebSocket,
+    Http,
+}
+
+pub fn listener_bound(listener: Listener) {
+    match listener {
+        Listener::WebSocket => WEBSOCKET_BOUND.store(true, Ordering::SeqCst),
+        Listener::Http => HTTP_BOUND.store(true, Ordering::SeqCst),
+    }
+}
+
+// Returns whether the server was already draining
+pub fn start_draining() -> bool {
+    DRAINING.swap(true, Ordering::SeqCst)
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub websocket: bool,
+    pub http: bool,
+    pub database: bool,
+    pub draining: bool,
+}
+
+impl Readiness {
+    pub fn new(database: bool) -> Self {
+        let websocket = WEBSOCKET_BOUND.load(Ordering::SeqCst);
+        let http = HTTP_BOUND.load(Ordering::SeqCst);
+        let draining = DRAINING.load(Ordering::SeqCst);
+        Self {
+            ready: websocket && http && database && !draining,
+            websocket,
+            http,
+            database,
+            draining,
+        }
+    }
+}
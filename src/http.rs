@@ -1,18 +1,150 @@
-use std::{collections::HashSet, net::SocketAddr, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    io::SeekFrom,
+    net::SocketAddr,
+    path::Path,
+    sync::{Arc, Mutex as StdMutex},
+    time::Duration,
+};
 
 use anyhow::Result;
-use futures::executor::block_on;
-use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
+use futures_util::FutureExt;
+use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full, LengthLimitError, Limited};
 use hyper::{
-    body::Bytes, header::HeaderValue, server::conn::http1::Builder, service::service_fn, HeaderMap,
-    Method, Request, Response, StatusCode,
+    body::{Bytes, Incoming},
+    header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, ETAG, IF_NONE_MATCH, RANGE},
+    server::conn::http1::Builder,
+    service::service_fn,
+    HeaderMap, Method, Request, Response, StatusCode,
 };
 use hyper_util::rt::TokioIo;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use serde_json::Map;
-use tokio::{net::TcpListener, sync::Mutex};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+    net::TcpListener,
+    sync::Mutex,
+    task::spawn_blocking,
+    time::timeout,
+};
+use tracing::{debug, error, info, info_span, Instrument};
+
+use crate::{
+    attachment::AttachmentError,
+    audit::{self, AuditFilter},
+    authcache::AuthCache,
+    channel::{ClientChannel, ClientInteractions},
+    client::{Client, Presence, UsernameError},
+    guest::{self, Guest},
+    health::{self, Listener, Readiness},
+    invite::{Invite, DEFAULT_LIFETIME, MAX_USES_LIMIT},
+    lockout::AuthError,
+    media::{self, Upload, AVATAR_UPLOAD_LIMIT},
+    message::Event,
+    metrics::METRICS,
+    registration::{DecideError, RegisterError, Registration, RegistrationStatus},
+    server::{unix_time, ClientExport},
+    token::{Scopes, Token},
+};
+
+const AUTH_CACHE_SIZE: usize = 1024;
+// Bounds how long a changed token keeps working over HTTP
+const AUTH_CACHE_TTL: Duration = Duration::from_secs(30);
+// Largest body accepted for small JSON requests
+const TOKEN_REQUEST_LIMIT: u64 = 4096;
+const TOKEN_NAME_LEN: usize = 64;
+// A database that takes longer than this to answer counts as unreachable
+const READY_TIMEOUT: Duration = Duration::from_secs(2);
+// The only GET paths open to guests, a trailing * matches any path below
+const GUEST_PATHS: [&str; 6] = [
+    "/list_clients",
+    "/history",
+    "/attachments/*",
+    "/avatars/*",
+    "/usernames/*",
+    "/clients/*",
+];
+
+#[derive(Deserialize)]
+struct TokenRequest {
+    name: String,
+    // Defaults to the scopes of the token making the request
+    scopes: Option<Scopes>,
+    // Seconds from now, never expires without
+    expires_in: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct InviteRequest {
+    #[serde(default = "default_max_uses")]
+    max_uses: u32,
+    // Seconds from now, DEFAULT_LIFETIME without
+    expires_in: Option<u64>,
+}
+
+fn default_max_uses() -> u32 {
+    1
+}
+
+#[derive(Deserialize)]
+struct RegisterRequest {
+    // Without a code the sign up waits for an admin's approval
+    code: Option<String>,
+    username: String,
+}
+
+#[derive(Deserialize)]
+struct GuestRequest {
+    username: String,
+    // Seconds from now, guest::DEFAULT_LIFETIME without
+    expires_in: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct CreatedGuest {
+    #[serde(flatten)]
+    guest: Guest,
+    config: ClientExport,
+}
+
+#[derive(Deserialize)]
+struct RenameRequest {
+    username: String,
+}
+
+#[derive(Deserialize)]
+struct ClaimRequest {
+    claim_token: String,
+}
+
+#[derive(Serialize)]
+struct PendingRegistration {
+    claim_token: Arc<str>,
+    #[serde(flatten)]
+    registration: Registration,
+}
+
+#[derive(Serialize)]
+struct CreatedInvite {
+    code: Arc<str>,
+    #[serde(flatten)]
+    invite: Invite,
+}
 
-use crate::channel::{ClientChannel, ClientInteractions};
+#[derive(Serialize)]
+struct CreatedToken {
+    token: Arc<str>,
+    #[serde(flatten)]
+    info: Token,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    Full,
+    Partial { start: u64, end: u64 },
+    Unsatisfiable,
+}
 
 pub fn json_bytes<T>(structure: T) -> Vec<u8>
 where
@@ -35,35 +167,7 @@ fn full<T: Into<Bytes>>(chunk: T) -> BoxBody<Bytes, hyper::Error> {
         .boxed()
 }
 
-async fn is_auth(
-    req: &Request<impl hyper::body::Body>,
-    client_channel: Arc<Mutex<ClientChannel>>,
-) -> bool {
-    req.headers()
-        .get("authorization")
-        .map(|f| {
-            if block_on(async {
-                client_channel
-                    .lock()
-                    .await
-                    .request(ClientInteractions::HttpValidateClient(
-                        f.to_str().unwrap().to_string(),
-                    ))
-                    .await
-                    .get_bool()
-            }) {
-                return true;
-            }
-            false
-        })
-        .unwrap_or(false)
-}
-
-pub async fn preflight(
-    _: Request<impl hyper::body::Body>,
-) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
-    let mut res = Response::new(empty());
-    *res.status_mut() = StatusCode::OK;
+fn cors_headers() -> HeaderMap {
     let mut headers = HeaderMap::new();
     headers.insert(
         "Access-Control-Allow-Origin",
@@ -75,27 +179,909 @@ pub async fn preflight(
     );
     headers.insert(
         "Access-Control-Allow-Methods",
-        HeaderValue::from_str("GET, POST, OPTIONS").unwrap(),
+        HeaderValue::from_str("GET, POST, PUT, DELETE, OPTIONS").unwrap(),
+    );
+    headers
+}
+
+fn status_response(status: StatusCode) -> Response<BoxBody<Bytes, hyper::Error>> {
+    Response::builder()
+        .status(status)
+        .body(full(format!(
+            "{}: {}\n",
+            status.as_u16(),
+            status.canonical_reason().unwrap_or_default()
+        )))
+        .unwrap()
+}
+
+fn json_response<T>(structure: T) -> Response<BoxBody<Bytes, hyper::Error>>
+where
+    T: Serialize,
+{
+    let mut res = Response::new(full(json_bytes(structure)));
+    let mut headers = cors_headers();
+    headers.insert(
+        "Content-Type",
+        HeaderValue::from_str("application/json").unwrap(),
+    );
+    *res.headers_mut() = headers;
+    res
+}
+
+fn query_params(req: &Request<impl hyper::body::Body>) -> HashMap<String, String> {
+    req.uri()
+        .query()
+        .map(|query| {
+            query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .map(|(key, value)| (key.to_string(), percent_decode(value)))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// None if a given filter does not parse
+fn audit_filter(params: &HashMap<String, String>) -> Option<AuditFilter> {
+    fn parse<T: std::str::FromStr>(value: Option<&String>) -> Option<Option<T>> {
+        match value {
+            None => Some(None),
+            Some(value) => value.parse().ok().map(Some),
+        }
+    }
+    Some(AuditFilter {
+        event: params.get("event").cloned(),
+        actor: params.get("actor").cloned(),
+        target: params.get("target").cloned(),
+        ip: parse(params.get("ip"))?,
+        since: parse(params.get("since"))?,
+        until: parse(params.get("until"))?,
+        before: parse(params.get("before"))?,
+        limit: parse(params.get("limit"))?.unwrap_or(audit::DEFAULT_LIMIT),
+    })
+}
+
+pub fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' => match value
+                .get(i + 1..i + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+            {
+                Some(byte) => {
+                    decoded.push(byte);
+                    i += 2;
+                }
+                None => decoded.push(b'%'),
+            },
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// Checks the token against every known client, connected or not
+async fn auth_client(
+    req: &Request<impl hyper::body::Body>,
+    addr: SocketAddr,
+    auth_cache: &StdMutex<AuthCache>,
+    client_channel: Arc<Mutex<ClientChannel>>,
+) -> Result<Client, AuthError> {
+    let Some(token) = req
+        .headers()
+        .get("authorization")
+        .and_then(|value| value.to_str().ok())
+    else {
+        return Err(AuthError::Invalid);
+    };
+    if let Some(client) = auth_cache.lock().unwrap().get(token) {
+        return Ok(client);
+    }
+    let client = client_channel
+        .lock()
+        .await
+        .request(ClientInteractions::HttpValidateClient {
+            ip: addr.ip(),
+            token: token.to_string(),
+        })
+        .await
+        .client_validation()?;
+    auth_cache.lock().unwrap().insert(token, client.clone());
+    Ok(client)
+}
+
+// Token management and account export or deletion need a full client token, invites,
+// guests, sign ups and the audit log need admin and everything else but reading needs send.
+// Guests only read the chat and profiles, listed in GUEST_PATHS, and have nothing to manage.
+fn is_permitted(method: &Method, path: &str, client: &Client) -> bool {
+    let scopes = client.scopes;
+    if client.is_guest {
+        return method == Method::GET
+            && GUEST_PATHS
+                .iter()
+                .any(|allowed| match allowed.strip_suffix('*') {
+                    Some(prefix) => path.starts_with(prefix),
+                    None => path == *allowed,
+                });
+    }
+    if path == "/me" || path == "/me/export" {
+        return scopes.can_manage_tokens();
+    }
+    if path == "/me/tokens" || path.starts_with("/me/tokens/") {
+        return scopes.can_manage_tokens();
+    }
+    if path == "/invites"
+        || path.starts_with("/invites/")
+        || path == "/guests"
+        || path.starts_with("/guests/")
+        || path == "/registrations"
+        || path.starts_with("/registrations/")
+        || path == "/audit"
+    {
+        return scopes.is_admin();
+    }
+    method == Method::GET || scopes.can_send()
+}
+
+// Single byte range of a Range header, anything malformed or multipart falls back to Full
+fn byte_range(header: Option<&HeaderValue>, size: u64) -> ByteRange {
+    let Some(spec) = header
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("bytes="))
+    else {
+        return ByteRange::Full;
+    };
+    let Some((start, end)) = spec.trim().split_once('-') else {
+        return ByteRange::Full;
+    };
+    let (start, end) = match (start.parse::<u64>(), end.parse::<u64>()) {
+        (Ok(start), Ok(end)) if start <= end => (start, end.min(size.saturating_sub(1))),
+        (Ok(start), Err(_)) if end.is_empty() => (start, size.saturating_sub(1)),
+        (Err(_), Ok(suffix)) if start.is_empty() && suffix > 0 => {
+            (size.saturating_sub(suffix), size.saturating_sub(1))
+        }
+        _ => return ByteRange::Full,
+    };
+    if start >= size {
+        ByteRange::Unsatisfiable
+    } else {
+        ByteRange::Partial { start, end }
+    }
+}
+
+fn range_not_satisfiable(size: u64) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut res = status_response(StatusCode::RANGE_NOT_SATISFIABLE);
+    res.headers_mut().insert(
+        "Content-Range",
+        HeaderValue::from_str(&format!("bytes */{size}")).unwrap(),
+    );
+    res
+}
+
+fn is_mime_type(value: &str) -> bool {
+    let is_token = |part: &str| {
+        !part.is_empty()
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "!#$&-^_.+".contains(c))
+    };
+    value
+        .split_once('/')
+        .is_some_and(|(kind, subtype)| is_token(kind) && is_token(subtype))
+}
+
+async fn read_range(path: &Path, start: u64, len: u64) -> std::io::Result<Vec<u8>> {
+    let mut file = File::open(path).await?;
+    file.seek(SeekFrom::Start(start)).await?;
+    let mut data = vec![0; len as usize];
+    file.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+// Whole request body, rejected early when it is declared or turns out larger than limit
+async fn read_body(req: Request<Incoming>, limit: u64) -> Result<Vec<u8>, StatusCode> {
+    let declared_size = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if declared_size.is_some_and(|size| size > limit) {
+        return Err(StatusCode::PAYLOAD_TOO_LARGE);
+    }
+    match Limited::new(req.into_body(), limit as usize)
+        .collect()
+        .await
+    {
+        Ok(body) => Ok(body.to_bytes().to_vec()),
+        Err(e) if e.is::<LengthLimitError>() => Err(StatusCode::PAYLOAD_TOO_LARGE),
+        Err(_) => Err(StatusCode::BAD_REQUEST),
+    }
+}
+
+async fn upload_attachment(
+    req: Request<Incoming>,
+    client: Client,
+    upload_limit: u64,
+    client_channel: Arc<Mutex<ClientChannel>>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let file_name = query_params(&req).remove("name").unwrap_or_default();
+    let content_type = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_ascii_lowercase())
+        .filter(|value| is_mime_type(value))
+        .unwrap_or("application/octet-stream".to_string());
+    let data = match read_body(req, upload_limit).await {
+        Ok(data) => data,
+        Err(status) => return status_response(status),
+    };
+    let Ok(upload) = spawn_blocking(move || Upload::process(data, content_type)).await else {
+        return status_response(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    let stored = client_channel
+        .lock()
+        .await
+        .request(ClientInteractions::HttpStoreAttachment {
+            owner_uuid: client.get_uuid(),
+            file_name,
+            upload,
+        })
+        .await
+        .stored_attachment();
+    match stored {
+        Ok(attachment) => {
+            let mut res = json_response(attachment);
+            *res.status_mut() = StatusCode::CREATED;
+            res
+        }
+        Err(AttachmentError::TooLarge) => status_response(StatusCode::PAYLOAD_TOO_LARGE),
+        Err(AttachmentError::QuotaExceeded) => status_response(StatusCode::INSUFFICIENT_STORAGE),
+        Err(AttachmentError::Storage) => status_response(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+async fn download_attachment(
+    req: &Request<Incoming>,
+    attachment_id: &str,
+    client_channel: Arc<Mutex<ClientChannel>>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let Some((attachment, path)) = client_channel
+        .lock()
+        .await
+        .request(ClientInteractions::HttpGetAttachment {
+            attachment_id: attachment_id.to_string(),
+            thumbnail: false,
+        })
+        .await
+        .attachment()
+    else {
+        return status_response(StatusCode::NOT_FOUND);
+    };
+    let size = attachment.size;
+    let (start, end, partial) = match byte_range(req.headers().get(RANGE), size) {
+        ByteRange::Full => (0, size, false),
+        ByteRange::Partial { start, end } => (start, end + 1, true),
+        ByteRange::Unsatisfiable => return range_not_satisfiable(size),
+    };
+    let data = match read_range(&path, start, end - start).await {
+        Ok(data) => data,
+        Err(e) => {
+            error!(attachment_id = %attachment.get_id(), error = %e, "failed to read attachment");
+            return status_response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let mut res = Response::new(full(data));
+    let mut headers = cors_headers();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_str(&attachment.content_type).unwrap(),
+    );
+    headers.insert("Accept-Ranges", HeaderValue::from_static("bytes"));
+    headers.insert(
+        "X-Content-Type-Options",
+        HeaderValue::from_static("nosniff"),
+    );
+    // Only media is shown inline, everything else is offered as a download
+    let disposition = if ["image/", "video/", "audio/"]
+        .iter()
+        .any(|kind| attachment.content_type.starts_with(kind))
+    {
+        "inline"
+    } else {
+        "attachment"
+    };
+    headers.insert(
+        "Content-Disposition",
+        HeaderValue::from_str(&format!(
+            "{disposition}; filename=\"{}\"",
+            attachment.file_name.escape_default()
+        ))
+        .unwrap_or(HeaderValue::from_static("attachment")),
+    );
+    if partial {
+        *res.status_mut() = StatusCode::PARTIAL_CONTENT;
+        headers.insert(
+            "Content-Range",
+            HeaderValue::from_str(&format!("bytes {start}-{}/{size}", end - 1)).unwrap(),
+        );
+    }
+    *res.headers_mut() = headers;
+    res
+}
+
+// Thumbnails are small so they are always sent whole
+async fn download_thumbnail(
+    attachment_id: &str,
+    client_channel: Arc<Mutex<ClientChannel>>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let Some((attachment, path)) = client_channel
+        .lock()
+        .await
+        .request(ClientInteractions::HttpGetAttachment {
+            attachment_id: attachment_id.to_string(),
+            thumbnail: true,
+        })
+        .await
+        .attachment()
+    else {
+        return status_response(StatusCode::NOT_FOUND);
+    };
+    let data = match tokio::fs::read(&path).await {
+        Ok(data) => data,
+        Err(e) => {
+            error!(attachment_id = %attachment.get_id(), error = %e, "failed to read thumbnail");
+            return status_response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    let content_type = image::guess_format(&data)
+        .map(|format| format.to_mime_type())
+        .unwrap_or("application/octet-stream");
+
+    let mut res = Response::new(full(data));
+    let mut headers = cors_headers();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(content_type));
+    headers.insert(
+        "X-Content-Type-Options",
+        HeaderValue::from_static("nosniff"),
     );
+    headers.insert("Content-Disposition", HeaderValue::from_static("inline"));
     *res.headers_mut() = headers;
+    res
+}
+
+pub async fn broadcast_profile(client: &Client, client_channel: Arc<Mutex<ClientChannel>>) {
+    let payload = Event::Profile {
+        uuid: client.get_uuid(),
+        username: client.username.clone(),
+        display_name: client.display_name.clone(),
+        about_me: client.about_me.clone(),
+        avatar: client.avatar.clone(),
+    }
+    .to_payload();
+    let connected = client_channel
+        .lock()
+        .await
+        .request(ClientInteractions::HttpGetConnectedClients)
+        .await
+        .connected_clients()
+        .unwrap_or_default();
+    connected
+        .values()
+        .filter_map(|client| client.tx.as_ref())
+        .for_each(|tx| {
+            let _ = tx.unbounded_send(payload.clone());
+        });
+}
+
+// A body replaces the avatar, None removes it
+async fn set_avatar(
+    req: Option<Request<Incoming>>,
+    client: Client,
+    client_channel: Arc<Mutex<ClientChannel>>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let avatar = match req {
+        Some(req) => {
+            let data = match read_body(req, AVATAR_UPLOAD_LIMIT).await {
+                Ok(data) => data,
+                Err(status) => return status_response(status),
+            };
+            match spawn_blocking(move || media::avatar(&data)).await {
+                Ok(Some(avatar)) => Some(avatar),
+                Ok(None) => return status_response(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+                Err(_) => return status_response(StatusCode::INTERNAL_SERVER_ERROR),
+            }
+        }
+        None => None,
+    };
+    let Some(client) = client_channel
+        .lock()
+        .await
+        .request(ClientInteractions::HttpSetAvatar {
+            uuid: client.get_uuid(),
+            avatar,
+        })
+        .await
+        .updated_client()
+    else {
+        return status_response(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    broadcast_profile(&client, client_channel).await;
+    json_response(client)
+}
+
+async fn rename(
+    req: Request<Incoming>,
+    client: Client,
+    addr: SocketAddr,
+    client_channel: Arc<Mutex<ClientChannel>>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let body = match read_body(req, TOKEN_REQUEST_LIMIT).await {
+        Ok(body) => body,
+        Err(status) => return status_response(status),
+    };
+    let Ok(request) = serde_json::from_slice::<RenameRequest>(&body) else {
+        return status_response(StatusCode::BAD_REQUEST);
+    };
+    let renamed = client_channel
+        .lock()
+        .await
+        .request(ClientInteractions::HttpRenameClient {
+            uuid: client.get_uuid(),
+            username: request.username.trim().to_string(),
+            ip: addr.ip(),
+        })
+        .await
+        .renamed_client();
+    match renamed {
+        Ok(client) => {
+            broadcast_profile(&client, client_channel).await;
+            json_response(client)
+        }
+        Err(UsernameError::Invalid) => status_response(StatusCode::BAD_REQUEST),
+        Err(UsernameError::Taken) => status_response(StatusCode::CONFLICT),
+    }
+}
+
+async fn get_avatar(
+    req: &Request<Incoming>,
+    uuid: &str,
+    client_channel: Arc<Mutex<ClientChannel>>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let Some((hash, path)) = client_channel
+        .lock()
+        .await
+        .request(ClientInteractions::HttpGetAvatar(uuid.to_string()))
+        .await
+        .avatar()
+    else {
+        return status_response(StatusCode::NOT_FOUND);
+    };
+    let etag = format!("\"{hash}\"");
+    let mut headers = cors_headers();
+    headers.insert(ETAG, HeaderValue::from_str(&etag).unwrap());
+    // The url stays the same when the avatar changes, so caches have to revalidate
+    headers.insert("Cache-Control", HeaderValue::from_static("no-cache"));
+    if req
+        .headers()
+        .get(IF_NONE_MATCH)
+        .is_some_and(|value| value.as_bytes() == etag.as_bytes())
+    {
+        let mut res = Response::new(empty());
+        *res.status_mut() = StatusCode::NOT_MODIFIED;
+        *res.headers_mut() = headers;
+        return res;
+    }
+    let data = match tokio::fs::read(&path).await {
+        Ok(data) => data,
+        Err(e) => {
+            error!(%uuid, error = %e, "failed to read avatar");
+            return status_response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(
+            image::guess_format(&data)
+                .map(|format| format.to_mime_type())
+                .unwrap_or("application/octet-stream"),
+        ),
+    );
+    headers.insert(
+        "X-Content-Type-Options",
+        HeaderValue::from_static("nosniff"),
+    );
+    let mut res = Response::new(full(data));
+    *res.headers_mut() = headers;
+    res
+}
+
+// New tokens can not have scopes the requesting token lacks
+async fn create_token(
+    req: Request<Incoming>,
+    client: Client,
+    addr: SocketAddr,
+    client_channel: Arc<Mutex<ClientChannel>>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let body = match read_body(req, TOKEN_REQUEST_LIMIT).await {
+        Ok(body) => body,
+        Err(status) => return status_response(status),
+    };
+    let Ok(request) = serde_json::from_slice::<TokenRequest>(&body) else {
+        return status_response(StatusCode::BAD_REQUEST);
+    };
+    let name = request.name.trim();
+    if name.is_empty() || name.chars().count() > TOKEN_NAME_LEN || request.expires_in == Some(0) {
+        return status_response(StatusCode::BAD_REQUEST);
+    }
+    let scopes = request.scopes.unwrap_or(client.scopes);
+    if scopes.is_empty() {
+        return status_response(StatusCode::BAD_REQUEST);
+    }
+    if !client.scopes.can_grant(&scopes) {
+        return status_response(StatusCode::FORBIDDEN);
+    }
+    let Some((info, token)) = client_channel
+        .lock()
+        .await
+        .request(ClientInteractions::HttpCreateToken {
+            client_uuid: client.get_uuid(),
+            name: name.to_string(),
+            scopes,
+            expires_at: request
+                .expires_in
+                .map(|seconds| unix_time().saturating_add(seconds)),
+            ip: addr.ip(),
+        })
+        .await
+        .created_token()
+    else {
+        return status_response(StatusCode::CONFLICT);
+    };
+    let mut res = json_response(CreatedToken { token, info });
+    *res.status_mut() = StatusCode::CREATED;
+    res
+}
+
+async fn revoke_token(
+    token_id: &str,
+    client: Client,
+    addr: SocketAddr,
+    auth_cache: &StdMutex<AuthCache>,
+    client_channel: Arc<Mutex<ClientChannel>>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let revoked = client_channel
+        .lock()
+        .await
+        .request(ClientInteractions::HttpRevokeToken {
+            client_uuid: client.get_uuid(),
+            token_id: token_id.to_string(),
+            ip: addr.ip(),
+        })
+        .await
+        .revoked();
+    if !revoked {
+        return status_response(StatusCode::NOT_FOUND);
+    }
+    auth_cache.lock().unwrap().remove_token_id(token_id);
+    let mut res = Response::new(empty());
+    *res.status_mut() = StatusCode::NO_CONTENT;
+    *res.headers_mut() = cors_headers();
+    res
+}
+
+async fn create_guest(
+    req: Request<Incoming>,
+    client: Client,
+    addr: SocketAddr,
+    client_channel: Arc<Mutex<ClientChannel>>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let body = match read_body(req, TOKEN_REQUEST_LIMIT).await {
+        Ok(body) => body,
+        Err(status) => return status_response(status),
+    };
+    let Ok(request) = serde_json::from_slice::<GuestRequest>(&body) else {
+        return status_response(StatusCode::BAD_REQUEST);
+    };
+    let expires_in = request.expires_in.unwrap_or(guest::DEFAULT_LIFETIME);
+    if expires_in == 0 || expires_in > guest::MAX_LIFETIME {
+        return status_response(StatusCode::BAD_REQUEST);
+    }
+    let created = client_channel
+        .lock()
+        .await
+        .request(ClientInteractions::HttpCreateGuest {
+            created_by: client.get_uuid(),
+            username: request.username.trim().to_string(),
+            expires_at: unix_time().saturating_add(expires_in),
+            ip: addr.ip(),
+        })
+        .await
+        .created_guest();
+    match created {
+        Ok((guest, config)) => {
+            let mut res = json_response(CreatedGuest { guest, config });
+            *res.status_mut() = StatusCode::CREATED;
+            res
+        }
+        Err(UsernameError::Invalid) => status_response(StatusCode::BAD_REQUEST),
+        Err(UsernameError::Taken) => status_response(StatusCode::CONFLICT),
+    }
+}
+
+async fn create_invite(
+    req: Request<Incoming>,
+    client: Client,
+    addr: SocketAddr,
+    client_channel: Arc<Mutex<ClientChannel>>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let body = match read_body(req, TOKEN_REQUEST_LIMIT).await {
+        Ok(body) => body,
+        Err(status) => return status_response(status),
+    };
+    let Ok(request) = serde_json::from_slice::<InviteRequest>(&body) else {
+        return status_response(StatusCode::BAD_REQUEST);
+    };
+    if request.max_uses == 0 || request.max_uses > MAX_USES_LIMIT {
+        return status_response(StatusCode::BAD_REQUEST);
+    }
+    let expires_in = request.expires_in.unwrap_or(DEFAULT_LIFETIME);
+    let Some((invite, code)) = client_channel
+        .lock()
+        .await
+        .request(ClientInteractions::HttpCreateInvite {
+            created_by: client.get_uuid(),
+            max_uses: request.max_uses,
+            expires_at: Some(unix_time().saturating_add(expires_in)),
+            ip: addr.ip(),
+        })
+        .await
+        .created_invite()
+    else {
+        return status_response(StatusCode::INTERNAL_SERVER_ERROR);
+    };
+    let mut res = json_response(CreatedInvite { code, invite });
+    *res.status_mut() = StatusCode::CREATED;
+    res
+}
+
+fn register_error_response(err: RegisterError) -> Response<BoxBody<Bytes, hyper::Error>> {
+    match err {
+        RegisterError::InvalidUsername => status_response(StatusCode::BAD_REQUEST),
+        RegisterError::UsernameTaken => status_response(StatusCode::CONFLICT),
+        RegisterError::InvalidInvite
+        | RegisterError::Closed
+        | RegisterError::UnknownClaim
+        | RegisterError::Rejected => status_response(StatusCode::FORBIDDEN),
+        RegisterError::QueueFull => status_response(StatusCode::SERVICE_UNAVAILABLE),
+        RegisterError::Pending => status_response(StatusCode::ACCEPTED),
+        RegisterError::AlreadyClaimed => status_response(StatusCode::GONE),
+        RegisterError::Throttled(retry_after) => {
+            let mut res = status_response(StatusCode::TOO_MANY_REQUESTS);
+            res.headers_mut()
+                .insert("Retry-After", HeaderValue::from(retry_after));
+            res
+        }
+    }
+}
+
+// Needs no token. With an invite code it is answered with the config a new client
+// connects with, without one with the claim token of the queued sign up.
+async fn register(
+    req: Request<Incoming>,
+    addr: SocketAddr,
+    client_channel: Arc<Mutex<ClientChannel>>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let body = match read_body(req, TOKEN_REQUEST_LIMIT).await {
+        Ok(body) => body,
+        Err(status) => return status_response(status),
+    };
+    let Ok(request) = serde_json::from_slice::<RegisterRequest>(&body) else {
+        return status_response(StatusCode::BAD_REQUEST);
+    };
+    let username = request.username.trim().to_string();
+    let Some(code) = request.code else {
+        let requested = client_channel
+            .lock()
+            .await
+            .request(ClientInteractions::HttpRequestRegistration {
+                ip: addr.ip(),
+                username,
+            })
+            .await
+            .requested_registration();
+        return match requested {
+            Ok((registration, claim_token)) => {
+                let mut res = json_response(PendingRegistration {
+                    claim_token,
+                    registration,
+                });
+                *res.status_mut() = StatusCode::ACCEPTED;
+                res
+            }
+            Err(err) => register_error_response(err),
+        };
+    };
+    let registration = client_channel
+        .lock()
+        .await
+        .request(ClientInteractions::HttpRegister {
+            ip: addr.ip(),
+            code,
+            username,
+        })
+        .await
+        .registration();
+    match registration {
+        Ok(export) => {
+            let mut res = json_response(export);
+            *res.status_mut() = StatusCode::CREATED;
+            res
+        }
+        Err(err) => register_error_response(err),
+    }
+}
+
+// Needs no token, 202 while the sign up is still pending
+async fn claim_registration(
+    req: Request<Incoming>,
+    addr: SocketAddr,
+    client_channel: Arc<Mutex<ClientChannel>>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let body = match read_body(req, TOKEN_REQUEST_LIMIT).await {
+        Ok(body) => body,
+        Err(status) => return status_response(status),
+    };
+    let Ok(request) = serde_json::from_slice::<ClaimRequest>(&body) else {
+        return status_response(StatusCode::BAD_REQUEST);
+    };
+    let claimed = client_channel
+        .lock()
+        .await
+        .request(ClientInteractions::HttpClaimRegistration {
+            ip: addr.ip(),
+            claim_token: request.claim_token,
+        })
+        .await
+        .registration();
+    match claimed {
+        Ok(export) => json_response(export),
+        Err(err) => register_error_response(err),
+    }
+}
+
+async fn decide_registration(
+    request_id: &str,
+    approve: bool,
+    client: Client,
+    addr: SocketAddr,
+    client_channel: Arc<Mutex<ClientChannel>>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let decided = client_channel
+        .lock()
+        .await
+        .request(ClientInteractions::HttpDecideRegistration {
+            request_id: request_id.to_string(),
+            approve,
+            decided_by: client.get_uuid(),
+            ip: addr.ip(),
+        })
+        .await
+        .decided_registration();
+    match decided {
+        Ok(registration) => json_response(registration),
+        Err(DecideError::Unknown) => status_response(StatusCode::NOT_FOUND),
+        Err(DecideError::AlreadyDecided | DecideError::UsernameTaken) => {
+            status_response(StatusCode::CONFLICT)
+        }
+    }
+}
+
+// Neither needs a token, so a supervisor can poll them
+fn health() -> Response<BoxBody<Bytes, hyper::Error>> {
+    let mut res = Response::new(full("OK\n"));
+    *res.headers_mut() = cors_headers();
+    res
+}
+
+async fn readiness(
+    client_channel: Arc<Mutex<ClientChannel>>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let database = timeout(READY_TIMEOUT, async {
+        client_channel
+            .lock()
+            .await
+            .request(ClientInteractions::HttpCheckDatabase)
+            .await
+            .database_reachable()
+    })
+    .await
+    .unwrap_or(false);
+    let readiness = Readiness::new(database);
+    let mut res = json_response(readiness);
+    if !readiness.ready {
+        *res.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    }
+    res
+}
+
+async fn server_info(
+    client_channel: Arc<Mutex<ClientChannel>>,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    let info = client_channel
+        .lock()
+        .await
+        .request(ClientInteractions::HttpServerInfo)
+        .await
+        .server_info();
+    match info {
+        Some(info) => json_response(info),
+        None => status_response(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+async fn preflight(
+    _: Request<impl hyper::body::Body>,
+) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
+    let mut res = Response::new(empty());
+    *res.status_mut() = StatusCode::OK;
+    *res.headers_mut() = cors_headers();
     Ok(res)
 }
 
 async fn handle_request(
-    req: Request<impl hyper::body::Body>,
-    _addr: SocketAddr,
+    req: Request<Incoming>,
+    addr: SocketAddr,
+    upload_limit: u64,
+    auth_cache: Arc<StdMutex<AuthCache>>,
     client_channel: Arc<Mutex<ClientChannel>>,
 ) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
     if req.method() == Method::OPTIONS {
         return preflight(req).await;
     };
-    if !is_auth(&req, client_channel.clone()).await {
-        let mut rej = Response::new(full(Bytes::from("UNAUTHORIZED\n")));
-        *rej.status_mut() = StatusCode::UNAUTHORIZED;
-        return Ok(rej);
+    if req.method() == Method::GET && req.uri().path() == "/healthz" {
+        return Ok(health());
+    }
+    if req.method() == Method::GET && req.uri().path() == "/readyz" {
+        return Ok(readiness(client_channel).await);
+    }
+    if req.method() == Method::GET && req.uri().path() == "/info" {
+        return Ok(server_info(client_channel).await);
+    }
+    if req.method() == Method::POST && req.uri().path() == "/register" {
+        return Ok(register(req, addr, client_channel).await);
+    }
+    if req.method() == Method::POST && req.uri().path() == "/register/claim" {
+        return Ok(claim_registration(req, addr, client_channel).await);
+    }
+    let client = match auth_client(&req, addr, &auth_cache, client_channel.clone()).await {
+        Ok(client) => client,
+        Err(AuthError::Invalid) => {
+            let mut rej = Response::new(full(Bytes::from("UNAUTHORIZED\n")));
+            *rej.status_mut() = StatusCode::UNAUTHORIZED;
+            return Ok(rej);
+        }
+        Err(AuthError::Throttled(retry_after)) => {
+            let mut rej = status_response(StatusCode::TOO_MANY_REQUESTS);
+            rej.headers_mut()
+                .insert("Retry-After", HeaderValue::from(retry_after));
+            return Ok(rej);
+        }
+    };
+    let path = req.uri().path().to_string();
+    if !is_permitted(req.method(), &path, &client) {
+        return Ok(status_response(StatusCode::FORBIDDEN));
     }
-    match (req.method(), req.uri().path()) {
-        (&Method::GET, "/list_clients") => {
+    match (req.method().clone(), path.as_str()) {
+        (Method::GET, "/list_clients") => {
             let all_clients = client_channel
                 .lock()
                 .await
@@ -109,14 +1095,21 @@ async fn handle_request(
                 .await
                 .connected_clients()
                 .unwrap();
-            let connected_clients = binding.values().collect::<HashSet<_>>();
+            // A client with several sessions is listed once
+            let mut connected_uuids = HashSet::new();
+            let connected_clients = binding
+                .values()
+                .filter(|c| c.presence != Presence::Invisible)
+                .filter(|c| connected_uuids.insert(c.get_uuid()))
+                .collect::<Vec<_>>();
             let mut map = Map::new();
             map.insert(
                 "offline".to_string(),
                 serde_json::to_value(
                     all_clients
                         .iter()
-                        .filter(|c| !connected_clients.contains(c))
+                        .filter(|c| !connected_uuids.contains(&c.get_uuid()))
+                        .map(Client::offline)
                         .collect::<Vec<_>>(),
                 )
                 .unwrap(),
@@ -125,27 +1118,226 @@ async fn handle_request(
                 "online".to_string(),
                 serde_json::to_value(connected_clients).unwrap(),
             );
-            let mut res = Response::new(full(json_bytes(serde_json::to_value(map).unwrap())));
-            let mut headers = HeaderMap::new();
-            headers.insert(
-                "Access-Control-Allow-Origin",
-                HeaderValue::from_str("*").unwrap(),
-            );
-            headers.insert(
-                "Access-Control-Allow-Headers",
-                HeaderValue::from_str("*").unwrap(),
-            );
-            headers.insert(
-                "Access-Control-Allow-Methods",
-                HeaderValue::from_str("GET, POST, OPTIONS").unwrap(),
+            Ok(json_response(map))
+        }
+        (Method::GET, "/history") => {
+            let params = query_params(&req);
+            let limit = params
+                .get("limit")
+                .and_then(|limit| limit.parse::<usize>().ok())
+                .unwrap_or(50);
+            let history = client_channel
+                .lock()
+                .await
+                .request(ClientInteractions::HttpGetHistory {
+                    thread: params.get("thread").cloned(),
+                    before: params.get("before").cloned(),
+                    limit,
+                })
+                .await
+                .history();
+            Ok(json_response(history))
+        }
+        (Method::POST, "/attachments") => {
+            Ok(upload_attachment(req, client, upload_limit, client_channel).await)
+        }
+        (Method::GET, path) if path.starts_with("/attachments/") => {
+            match path["/attachments/".len()..].split_once('/') {
+                None => {
+                    let attachment_id = &path["/attachments/".len()..];
+                    Ok(download_attachment(&req, attachment_id, client_channel).await)
+                }
+                Some((attachment_id, "thumbnail")) => {
+                    Ok(download_thumbnail(attachment_id, client_channel).await)
+                }
+                Some(_) => Ok(status_response(StatusCode::NOT_FOUND)),
+            }
+        }
+        (Method::PUT, "/me/avatar") => Ok(set_avatar(Some(req), client, client_channel).await),
+        (Method::DELETE, "/me/avatar") => Ok(set_avatar(None, client, client_channel).await),
+        (Method::GET, "/me/export") => {
+            let Some(export) = client_channel
+                .lock()
+                .await
+                .request(ClientInteractions::HttpExportAccount(client.get_uuid()))
+                .await
+                .account_export()
+            else {
+                return Ok(status_response(StatusCode::NOT_FOUND));
+            };
+            let mut res = json_response(export);
+            res.headers_mut().insert(
+                "Content-Disposition",
+                HeaderValue::from_static("attachment; filename=\"tensor-export.json\""),
             );
-            *res.headers_mut() = headers;
             Ok(res)
         }
-        _ => Ok(Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(full(Bytes::from("404: Not Found\n")))
-            .unwrap()),
+        (Method::DELETE, "/me") => {
+            let deleted = client_channel
+                .lock()
+                .await
+                .request(ClientInteractions::HttpDeleteAccount {
+                    uuid: client.get_uuid(),
+                    ip: addr.ip(),
+                })
+                .await
+                .deleted();
+            if !deleted {
+                return Ok(status_response(StatusCode::NOT_FOUND));
+            }
+            auth_cache.lock().unwrap().remove_client(&client.get_uuid());
+            let mut res = Response::new(empty());
+            *res.status_mut() = StatusCode::NO_CONTENT;
+            *res.headers_mut() = cors_headers();
+            Ok(res)
+        }
+        (Method::PUT, "/me/username") => Ok(rename(req, client, addr, client_channel).await),
+        // Resolves past names too, presence is left to /list_clients
+        (Method::GET, path) if path.starts_with("/usernames/") => {
+            let username = path["/usernames/".len()..].to_string();
+            let Some(found) = client_channel
+                .lock()
+                .await
+                .request(ClientInteractions::HttpFindClient(username))
+                .await
+                .updated_client()
+            else {
+                return Ok(status_response(StatusCode::NOT_FOUND));
+            };
+            Ok(json_response(found.offline()))
+        }
+        (Method::GET, path) if path.starts_with("/clients/") => {
+            match path["/clients/".len()..].split_once('/') {
+                Some((uuid, "usernames")) => {
+                    let history = client_channel
+                        .lock()
+                        .await
+                        .request(ClientInteractions::HttpGetUsernameHistory(uuid.to_string()))
+                        .await
+                        .username_history();
+                    Ok(json_response(history))
+                }
+                _ => Ok(status_response(StatusCode::NOT_FOUND)),
+            }
+        }
+        (Method::GET, path) if path.starts_with("/avatars/") => {
+            let uuid = &path["/avatars/".len()..];
+            Ok(get_avatar(&req, uuid, client_channel).await)
+        }
+        (Method::GET, "/me/tokens") => {
+            let tokens = client_channel
+                .lock()
+                .await
+                .request(ClientInteractions::HttpGetTokens(client.get_uuid()))
+                .await
+                .tokens();
+            Ok(json_response(tokens))
+        }
+        (Method::POST, "/me/tokens") => Ok(create_token(req, client, addr, client_channel).await),
+        (Method::DELETE, path) if path.starts_with("/me/tokens/") => {
+            let token_id = &path["/me/tokens/".len()..];
+            Ok(revoke_token(token_id, client, addr, &auth_cache, client_channel).await)
+        }
+        (Method::GET, "/invites") => {
+            let invites = client_channel
+                .lock()
+                .await
+                .request(ClientInteractions::HttpGetInvites)
+                .await
+                .invites();
+            Ok(json_response(invites))
+        }
+        (Method::GET, "/guests") => {
+            let guests = client_channel
+                .lock()
+                .await
+                .request(ClientInteractions::HttpGetGuests)
+                .await
+                .guests();
+            Ok(json_response(guests))
+        }
+        (Method::POST, "/guests") => Ok(create_guest(req, client, addr, client_channel).await),
+        (Method::DELETE, path) if path.starts_with("/guests/") => {
+            let guest_id = &path["/guests/".len()..];
+            let revoked = client_channel
+                .lock()
+                .await
+                .request(ClientInteractions::HttpRevokeGuest {
+                    guest_id: guest_id.to_string(),
+                    revoked_by: client.get_uuid(),
+                    ip: addr.ip(),
+                })
+                .await
+                .revoked();
+            if !revoked {
+                return Ok(status_response(StatusCode::NOT_FOUND));
+            }
+            auth_cache.lock().unwrap().remove_token_id(guest_id);
+            let mut res = Response::new(empty());
+            *res.status_mut() = StatusCode::NO_CONTENT;
+            *res.headers_mut() = cors_headers();
+            Ok(res)
+        }
+        (Method::POST, "/invites") => Ok(create_invite(req, client, addr, client_channel).await),
+        (Method::DELETE, path) if path.starts_with("/invites/") => {
+            let revoked = client_channel
+                .lock()
+                .await
+                .request(ClientInteractions::HttpRevokeInvite {
+                    invite_id: path["/invites/".len()..].to_string(),
+                    revoked_by: client.get_uuid(),
+                    ip: addr.ip(),
+                })
+                .await
+                .revoked();
+            if !revoked {
+                return Ok(status_response(StatusCode::NOT_FOUND));
+            }
+            let mut res = Response::new(empty());
+            *res.status_mut() = StatusCode::NO_CONTENT;
+            *res.headers_mut() = cors_headers();
+            Ok(res)
+        }
+        (Method::GET, "/registrations") => {
+            let status = match query_params(&req).get("status") {
+                None => None,
+                Some(status) => match RegistrationStatus::parse(status) {
+                    Some(status) => Some(status),
+                    None => return Ok(status_response(StatusCode::BAD_REQUEST)),
+                },
+            };
+            let registrations = client_channel
+                .lock()
+                .await
+                .request(ClientInteractions::HttpGetRegistrations(status))
+                .await
+                .registrations();
+            Ok(json_response(registrations))
+        }
+        (Method::GET, "/audit") => {
+            let Some(filter) = audit_filter(&query_params(&req)) else {
+                return Ok(status_response(StatusCode::BAD_REQUEST));
+            };
+            let entries = client_channel
+                .lock()
+                .await
+                .request(ClientInteractions::HttpGetAuditLog(filter))
+                .await
+                .audit_entries();
+            Ok(json_response(entries))
+        }
+        (Method::POST, path) if path.starts_with("/registrations/") => {
+            match path["/registrations/".len()..].split_once('/') {
+                Some((request_id, "approve")) => {
+                    Ok(decide_registration(request_id, true, client, addr, client_channel).await)
+                }
+                Some((request_id, "reject")) => {
+                    Ok(decide_registration(request_id, false, client, addr, client_channel).await)
+                }
+                _ => Ok(status_response(StatusCode::NOT_FOUND)),
+            }
+        }
+        _ => Ok(status_response(StatusCode::NOT_FOUND)),
     }
 }
 
@@ -155,21 +1347,117 @@ pub async fn http_main(mut client: ClientChannel) -> Result<()> {
         .await
         .socket_addr()
         .unwrap();
+    let upload_limit = client
+        .request(ClientInteractions::HttpUploadLimit)
+        .await
+        .upload_limit();
 
     // Create the event loop and TCP listener we'll accept connections on.
     let try_socket = TcpListener::bind(&addr).await;
     let listener = try_socket.expect("Failed to bind");
-    println!("Listening to HTTP Requests on: {}", addr);
+    info!(%addr, "listening for http requests");
+    health::listener_bound(Listener::Http);
 
     let client_channel = Arc::new(Mutex::new(client));
+    let auth_cache = Arc::new(StdMutex::new(AuthCache::new(
+        AUTH_CACHE_SIZE,
+        AUTH_CACHE_TTL,
+    )));
 
     // Let's spawn the handling of each connection in a separate task.
     while let Ok((stream, addr)) = listener.accept().await {
-        let req_wrapper = |req| handle_request(req, addr, client_channel.clone());
-        let io = TokioIo::new(stream);
-        let _ = Builder::new()
-            .serve_connection(io, service_fn(req_wrapper))
-            .await;
+        let auth_cache = auth_cache.clone();
+        let client_channel = client_channel.clone();
+        tokio::spawn(async move {
+            let req_wrapper = |req: Request<Incoming>| {
+                let span = info_span!(
+                    "request",
+                    %addr,
+                    method = %req.method(),
+                    path = req.uri().path(),
+                );
+                handle_request(
+                    req,
+                    addr,
+                    upload_limit,
+                    auth_cache.clone(),
+                    client_channel.clone(),
+                )
+                .inspect(|res| {
+                    if let Ok(res) = res {
+                        METRICS.http_response(res.status());
+                        debug!(status = res.status().as_u16(), "responded");
+                    }
+                })
+                .instrument(span)
+            };
+            let io = TokioIo::new(stream);
+            let _ = Builder::new()
+                .serve_connection(io, service_fn(req_wrapper))
+                .await;
+        });
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(header: &str, size: u64) -> ByteRange {
+        byte_range(Some(&HeaderValue::from_str(header).unwrap()), size)
+    }
+
+    #[test]
+    fn byte_range_bounds() {
+        assert_eq!(byte_range(None, 100), ByteRange::Full);
+        assert_eq!(
+            range("bytes=10-19", 100),
+            ByteRange::Partial { start: 10, end: 19 }
+        );
+        // Open ended and past the end run to the last byte
+        assert_eq!(
+            range("bytes=90-", 100),
+            ByteRange::Partial { start: 90, end: 99 }
+        );
+        assert_eq!(
+            range("bytes=90-500", 100),
+            ByteRange::Partial { start: 90, end: 99 }
+        );
+        // Suffixes count from the end, longer ones cover the whole file
+        assert_eq!(
+            range("bytes=-10", 100),
+            ByteRange::Partial { start: 90, end: 99 }
+        );
+        assert_eq!(
+            range("bytes=-500", 100),
+            ByteRange::Partial { start: 0, end: 99 }
+        );
+        assert_eq!(range("bytes=100-", 100), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=200-300", 100), ByteRange::Unsatisfiable);
+        assert_eq!(range("bytes=0-", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn byte_range_falls_back_to_full() {
+        for header in [
+            "bytes=20-10",
+            "bytes=0-1,5-6",
+            "bytes=-0",
+            "bytes=-",
+            "bytes=a-b",
+            "bytes=5",
+            "items=0-10",
+            "bytes=18446744073709551616-",
+        ] {
+            assert_eq!(range(header, 100), ByteRange::Full, "{header}");
+        }
+    }
+
+    #[test]
+    fn unsatisfiable_range_response() {
+        let res = range_not_satisfiable(100);
+        assert_eq!(res.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(res.headers()["Content-Range"], "bytes */100");
+    }
+}
@@ -0,0 +1,95 @@
+// File Contains What the Server Tells Clients About Itself
+//
+// Served without authentication on GET /info, so a client can find out what it
+// is talking to before it has a token. Only the name and the MOTD come from the
+// config, everything else is fixed by this build.
+
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::{
+    attachment::ATTACHMENTS_PER_MESSAGE,
+    client::{USERNAME_LIMIT, USERNAME_MIN},
+    media::AVATAR_UPLOAD_LIMIT,
+    message::STATUS_TEXT_LIMIT,
+    reaction::EMOJI_LIMIT,
+    registration::RegistrationMode,
+    server::{Server, WsAuthMethods, HISTORY_LIMIT, TOKENS_PER_CLIENT},
+};
+
+// Bumped whenever websocket or HTTP payloads change in a way older clients can't read
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Features {
+    // Listeners are plain TCP, TLS is left to a proxy in front of the server
+    pub tls: bool,
+    pub attachments: bool,
+    pub avatars: bool,
+    pub reactions: bool,
+    pub threads: bool,
+    pub guests: bool,
+    pub voice: bool,
+    pub registration: RegistrationMode,
+    pub websocket_auth: WsAuthMethods,
+}
+
+// Sizes are in bytes, lengths in characters
+#[derive(Debug, Clone, Serialize)]
+pub struct Limits {
+    pub upload_size: u64,
+    pub attachment_quota: u64,
+    pub attachments_per_message: usize,
+    pub avatar_upload_size: u64,
+    pub username_min: usize,
+    pub username_max: usize,
+    pub status_text_length: usize,
+    pub emoji_length: usize,
+    pub history_page: usize,
+    pub tokens_per_client: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ServerInfo {
+    pub server_name: Arc<str>,
+    pub version: &'static str,
+    pub protocol_version: u32,
+    pub features: Features,
+    pub limits: Limits,
+    pub motd: Option<Arc<str>>,
+}
+
+impl ServerInfo {
+    pub fn new(server: &Server) -> Self {
+        Self {
+            server_name: server.server_name.clone(),
+            version: env!("CARGO_PKG_VERSION"),
+            protocol_version: PROTOCOL_VERSION,
+            features: Features {
+                tls: false,
+                attachments: true,
+                avatars: true,
+                reactions: true,
+                threads: true,
+                guests: true,
+                voice: false,
+                registration: server.registration,
+                websocket_auth: server.websocket_auth,
+            },
+            limits: Limits {
+                upload_size: server.max_upload_size,
+                attachment_quota: server.attachment_quota,
+                attachments_per_message: ATTACHMENTS_PER_MESSAGE,
+                avatar_upload_size: AVATAR_UPLOAD_LIMIT,
+                username_min: USERNAME_MIN,
+                username_max: USERNAME_LIMIT,
+                status_text_length: STATUS_TEXT_LIMIT,
+                emoji_length: EMOJI_LIMIT,
+                history_page: HISTORY_LIMIT,
+                tokens_per_client: TOKENS_PER_CLIENT,
+            },
+            motd: server.motd.clone(),
+        }
+    }
+}
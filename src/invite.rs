@@ -0,0 +1,144 @@
+// File Contains Invite Codes Admins Hand Out for Self Registration
+//
+// Codes look like tokens ("tensorinvite_<invite id>.<secret>") and are only
+// shown once, the invite is stored and audited by its id with the code's hash.
+
+use std::sync::Arc;
+
+use serde::Serialize;
+use sqlite::{Connection, Row, Value};
+
+use crate::{
+    server::unix_time,
+    token::{self, TokenKey},
+};
+
+pub const INVITE_PREFIX: &str = "tensorinvite";
+pub const MAX_USES_LIMIT: u32 = 1000;
+// Used when an admin does not say how long an invite lasts
+pub const DEFAULT_LIFETIME: u64 = 7 * 24 * 60 * 60;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Invite {
+    invite_id: Arc<str>,
+    #[serde(skip)]
+    code_hash: Arc<str>,
+    created_by: Arc<str>,
+    pub max_uses: u32,
+    pub uses: u32,
+    pub expires_at: Option<u64>,
+    pub created_at: u64,
+}
+
+impl Invite {
+    // The code is only ever known here, only its hash is stored
+    pub fn new(
+        connection: &Connection,
+        key: &TokenKey,
+        created_by: Arc<str>,
+        max_uses: u32,
+        expires_at: Option<u64>,
+    ) -> (Self, Arc<str>) {
+        let mut invite_id = token::generate_token_id();
+        while Self::get(connection, &invite_id).is_some() {
+            invite_id = token::generate_token_id();
+        }
+        let code = token::generate(Some(INVITE_PREFIX), &invite_id);
+        let s = Self {
+            invite_id,
+            code_hash: key.hash(&code).into(),
+            created_by,
+            max_uses,
+            uses: 0,
+            expires_at,
+            created_at: unix_time(),
+        };
+        s.write_to_db(connection);
+        (s, code)
+    }
+
+    pub fn from_db_row(row: Row) -> Self {
+        Self {
+            invite_id: row.read::<&str, _>("invite_id").into(),
+            code_hash: row.read::<&str, _>("code_hash").into(),
+            created_by: row.read::<&str, _>("created_by").into(),
+            max_uses: row.read::<i64, _>("max_uses") as u32,
+            uses: row.read::<i64, _>("uses") as u32,
+            expires_at: row.read::<Option<i64>, _>("expires_at").map(|t| t as u64),
+            created_at: row.read::<i64, _>("created_at") as u64,
+        }
+    }
+
+    pub fn write_to_db(&self, connection: &Connection) {
+        let query = "INSERT INTO invites (invite_id, code_hash, created_by, max_uses, uses, expires_at, created_at) VALUES (?, ?, ?, ?, ?, ?, ?)";
+        let mut statement = connection.prepare(query).unwrap();
+        statement
+            .bind_iter::<_, (_, Value)>([
+                (1, self.invite_id.as_ref().into()),
+                (2, self.code_hash.as_ref().into()),
+                (3, self.created_by.as_ref().into()),
+                (4, (self.max_uses as i64).into()),
+                (5, (self.uses as i64).into()),
+                (6, self.expires_at.map(|t| t as i64).into()),
+                (7, (self.created_at as i64).into()),
+            ])
+            .unwrap();
+        let _ = statement.next();
+    }
+
+    pub fn get(connection: &Connection, invite_id: &str) -> Option<Self> {
+        let query = "SELECT * FROM invites WHERE invite_id = ?";
+        connection
+            .prepare(query)
+            .unwrap()
+            .into_iter()
+            .bind((1, invite_id))
+            .unwrap()
+            .map(|row| Self::from_db_row(row.unwrap()))
+            .next()
+    }
+
+    // Newest first
+    pub fn all(connection: &Connection) -> Vec<Self> {
+        let query = "SELECT * FROM invites ORDER BY created_at DESC, rowid DESC";
+        connection
+            .prepare(query)
+            .unwrap()
+            .into_iter()
+            .map(|row| Self::from_db_row(row.unwrap()))
+            .collect()
+    }
+
+    // Uses the invite up once and returns its id, None if the code is unknown,
+    // expired or used up
+    pub fn redeem(connection: &Connection, key: &TokenKey, code: &str) -> Option<Arc<str>> {
+        let invite = Self::get(connection, &key.token_id(code))?;
+        if !key.verify(code, &invite.code_hash) {
+            return None;
+        }
+        let query = "UPDATE invites SET uses = uses + 1 WHERE invite_id = ? AND uses < max_uses AND (expires_at IS NULL OR expires_at > ?)";
+        let mut statement = connection.prepare(query).unwrap();
+        statement
+            .bind_iter::<_, (_, Value)>([
+                (1, invite.invite_id.as_ref().into()),
+                (2, (unix_time() as i64).into()),
+            ])
+            .unwrap();
+        let _ = statement.next();
+        (connection.change_count() > 0).then_some(invite.invite_id)
+    }
+
+    // Returns whether the invite existed
+    pub fn revoke(connection: &Connection, invite_id: &str) -> bool {
+        let mut statement = connection
+            .prepare("DELETE FROM invites WHERE invite_id = ?")
+            .unwrap();
+        statement.bind((1, invite_id)).unwrap();
+        let _ = statement.next();
+        connection.change_count() > 0
+    }
+
+    pub fn get_id(&self) -> Arc<str> {
+        self.invite_id.clone()
+    }
+}
@@ -0,0 +1,23 @@
+pub mod account;
+pub mod attachment;
+pub mod audit;
+pub mod authcache;
+pub mod blob;
+pub mod channel;
+pub mod client;
+pub mod guest;
+pub mod health;
+pub mod http;
+pub mod info;
+pub mod invite;
+pub mod lockout;
+pub mod logging;
+pub mod media;
+pub mod message;
+pub mod metrics;
+pub mod ratelimit;
+pub mod reaction;
+pub mod registration;
+pub mod server;
+pub mod token;
+pub mod websocket;
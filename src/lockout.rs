@@ -0,0 +1,252 @@
+// File Contains Per Address Throttling of Failed Token Validations
+//
+// The first `free_attempts` failures cost nothing, every one after that makes the
+// address wait twice as long before it may try again, up to `max_backoff_secs`.
+// Reaching `lockout_after` failures locks the address out for `lockout_secs`.
+// Failures are forgotten once an address has been quiet for `window_secs`.
+
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv6Addr},
+};
+
+use serde::Deserialize;
+use sqlite::Connection;
+
+use crate::audit::AuditEntry;
+
+// Addresses tracked before quiet ones are dropped
+const MAX_TRACKED: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    Invalid,
+    // Seconds until the address may try again
+    Throttled(u64),
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+pub struct LockoutConfig {
+    pub free_attempts: u32,
+    pub backoff_secs: u64,
+    pub max_backoff_secs: u64,
+    pub lockout_after: u32,
+    pub lockout_secs: u64,
+    pub window_secs: u64,
+    // Failures against one existing token, from any address, before it is reported
+    pub token_alert_after: u32,
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        Self {
+            free_attempts: 5,
+            backoff_secs: 1,
+            max_backoff_secs: 300,
+            lockout_after: 20,
+            lockout_secs: 15 * 60,
+            window_secs: 60 * 60,
+            token_alert_after: 10,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Failures {
+    count: u32,
+    last: u64,
+    locked_until: u64,
+}
+
+#[derive(Default)]
+pub struct Lockout {
+    config: LockoutConfig,
+    addresses: HashMap<IpAddr, Failures>,
+    // Failures and time of the first one for each token id
+    tokens: HashMap<String, (u32, u64)>,
+}
+
+// Hosts usually get a whole IPv6 /64, so it counts as one address
+fn address_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => IpAddr::V4(ip),
+            None => {
+                let s = ip.segments();
+                IpAddr::V6(Ipv6Addr::new(s[0], s[1], s[2], s[3], 0, 0, 0, 0))
+            }
+        },
+        ip => ip,
+    }
+}
+
+impl Lockout {
+    pub fn new(config: LockoutConfig) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    fn backoff(&self, count: u32) -> u64 {
+        match count.checked_sub(self.config.free_attempts) {
+            None | Some(0) => 0,
+            Some(over) => self
+                .config
+                .backoff_secs
+                .saturating_mul(1u64.checked_shl(over - 1).unwrap_or(u64::MAX))
+                .min(self.config.max_backoff_secs),
+        }
+    }
+
+    // Seconds the address has to wait before its next attempt, None if it may try now
+    pub fn retry_after(&self, ip: IpAddr, now: u64) -> Option<u64> {
+        let failures = self.addresses.get(&address_key(ip))?;
+        let until = failures
+            .locked_until
+            .max(failures.last + self.backoff(failures.count));
+        (until > now).then(|| until - now)
+    }
+
+    // known_token is the id of the token that was tried when such a token exists.
+    // Failures against it are audited until it is reported as targeted, others only
+    // show up when an address starts backing off or is locked out.
+    pub fn record_failure(
+        &mut self,
+        ip: IpAddr,
+        known_token: Option<&str>,
+        now: u64,
+        connection: &Connection,
+    ) {
+        self.prune(now);
+        let config = self.config;
+        let failures = self.addresses.entry(address_key(ip)).or_default();
+        if now >= failures.last + config.window_secs {
+            *failures = Failures::default();
+        }
+        failures.count += 1;
+        failures.last = now;
+        let count = failures.count;
+        if count >= config.lockout_after {
+            failures.locked_until = now + config.lockout_secs;
+            // Still backing off once the lockout ends
+            failures.count = config.free_attempts + 1;
+            AuditEntry::record(
+                connection,
+                "auth_lockout",
+                None,
+                None,
+                Some(ip),
+                &format!(
+                    "{count} failed attempts, locked out for {}s",
+                    config.lockout_secs
+                ),
+            );
+        } else if count == config.free_attempts + 1 {
+            AuditEntry::record(
+                connection,
+                "auth_backoff",
+                None,
+                None,
+                Some(ip),
+                &format!("{count} failed attempts"),
+            );
+        }
+
+        let Some(token_id) = known_token else {
+            return;
+        };
+        let (count, first) = self.tokens.entry(token_id.to_string()).or_default();
+        if now >= *first + config.window_secs {
+            (*count, *first) = (0, now);
+        }
+        *count += 1;
+        if *count < config.token_alert_after {
+            AuditEntry::record(
+                connection,
+                "auth_failed",
+                None,
+                Some(token_id),
+                Some(ip),
+                "",
+            );
+        } else if *count == config.token_alert_after {
+            AuditEntry::record(
+                connection,
+                "auth_token_targeted",
+                None,
+                Some(token_id),
+                Some(ip),
+                &format!("{count} failed attempts"),
+            );
+        }
+    }
+
+    // Forgets quiet addresses and tokens once too many are tracked
+    fn prune(&mut self, now: u64) {
+        let window = self.config.window_secs;
+        if self.addresses.len() >= MAX_TRACKED {
+            self.addresses
+                .retain(|_, f| now < f.last + window || now < f.locked_until);
+        }
+        if self.addresses.len() >= MAX_TRACKED {
+            let oldest = self
+                .addresses
+                .iter()
+                .filter(|(_, f)| now >= f.locked_until)
+                .min_by_key(|(_, f)| f.last)
+                .map(|(ip, _)| *ip);
+            if let Some(oldest) = oldest {
+                self.addresses.remove(&oldest);
+            }
+        }
+        if self.tokens.len() >= MAX_TRACKED {
+            self.tokens.retain(|_, (_, first)| now < *first + window);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn audit_db() -> Connection {
+        let connection = sqlite::open(":memory:").unwrap();
+        connection
+            .execute("CREATE TABLE audit_log (entry_id INTEGER PRIMARY KEY AUTOINCREMENT, unix_time INTEGER NOT NULL, event TEXT NOT NULL, actor TEXT, target TEXT, ip TEXT, detail TEXT NOT NULL);")
+            .unwrap();
+        connection
+    }
+
+    fn audit_events(connection: &Connection) -> Vec<String> {
+        connection
+            .prepare("SELECT event FROM audit_log ORDER BY entry_id")
+            .unwrap()
+            .into_iter()
+            .map(|row| row.unwrap().read::<&str, _>("event").to_string())
+            .collect()
+    }
+
+    #[test]
+    fn failures_are_audited_sparingly() {
+        let connection = audit_db();
+        let config = LockoutConfig::default();
+        let mut lockout = Lockout::new(config);
+        let ip = IpAddr::from([192, 0, 2, 1]);
+        for _ in 0..config.lockout_after {
+            lockout.record_failure(ip, None, 0, &connection);
+        }
+        assert_eq!(audit_events(&connection), ["auth_backoff", "auth_lockout"]);
+
+        let connection = audit_db();
+        let mut lockout = Lockout::new(config);
+        for i in 0..config.token_alert_after * 3 {
+            let ip = IpAddr::from([198, 51, 100, i as u8]);
+            lockout.record_failure(ip, Some("token"), 0, &connection);
+        }
+        let events = audit_events(&connection);
+        assert_eq!(events.len(), config.token_alert_after as usize);
+        assert_eq!(events.last().unwrap(), "auth_token_targeted");
+    }
+}
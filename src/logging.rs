@@ -0,0 +1,81 @@
+// File Contains Setup of Leveled, Structured Logs
+//
+// `level` takes tracing's filter syntax ("info", "warn,tensor=debug") and is
+// overridden by the TENSOR_LOG environment variable. Message contents only show
+// up in logs with `log_message_content` on.
+
+use std::{
+    io::{self, IsTerminal},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+pub const LOG_ENV: &str = "TENSOR_LOG";
+const REDACTED: &str = "[redacted]";
+
+static LOG_MESSAGE_CONTENT: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    // Human readable lines
+    #[default]
+    Text,
+    // One JSON object per line, with the fields of its spans
+    Json,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LogConfig {
+    pub level: String,
+    pub format: LogFormat,
+    pub log_message_content: bool,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Text,
+            log_message_content: false,
+        }
+    }
+}
+
+// Logs go to stdout, does nothing if already set up
+pub fn init(config: &LogConfig) {
+    LOG_MESSAGE_CONTENT.store(config.log_message_content, Ordering::Relaxed);
+    let (filter, invalid) = match EnvFilter::try_from_env(LOG_ENV) {
+        Ok(filter) => (filter, None),
+        Err(_) => match EnvFilter::try_new(&config.level) {
+            Ok(filter) => (filter, None),
+            Err(e) => (EnvFilter::new("info"), Some(e)),
+        },
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_ansi(io::stdout().is_terminal());
+    let _ = match config.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder
+            .json()
+            .flatten_event(true)
+            .with_span_list(true)
+            .try_init(),
+    };
+    if let Some(e) = invalid {
+        tracing::warn!(level = %config.level, error = %e, "invalid log level, using info");
+    }
+}
+
+// The content of a message as it may appear in logs
+pub fn message_content(content: &str) -> &str {
+    if LOG_MESSAGE_CONTENT.load(Ordering::Relaxed) {
+        content
+    } else {
+        REDACTED
+    }
+}
@@ -1,18 +1,17 @@
-pub mod channel;
-pub mod client;
-pub mod http;
-pub mod message;
-pub mod server;
-pub mod websocket;
-
 use anyhow::Result;
-use channel::{interaction_channel, ClientInteractions, Clients, ServerInteractions};
-use std::{path::PathBuf, process::exit};
+use std::{path::PathBuf, process::exit, thread, time::Duration};
+use tensor::channel::{interaction_channel, ClientInteractions, Clients, ServerInteractions};
 
 use argh::FromArgs;
-use http::http_main;
-use server::Server;
-use websocket::websocket_main;
+use tensor::audit::CLI_ACTOR;
+use tensor::client::UsernameError;
+use tensor::health;
+use tensor::http::http_main;
+use tensor::info::ServerInfo;
+use tensor::metrics::metrics_main;
+use tensor::registration::{DecideError, RegistrationStatus};
+use tensor::server::Server;
+use tensor::websocket::websocket_main;
 
 #[derive(FromArgs, Debug)]
 ///Tensor Server
@@ -21,6 +20,26 @@ struct Args {
     #[argh(option, short = 'n', long = "new", arg_name = "name")]
     name: Option<String>,
 
+    /// give the client generated with --new the admin scope
+    #[argh(switch)]
+    admin: bool,
+
+    /// generate a temporary guest with --new instead of a client
+    #[argh(switch)]
+    guest: bool,
+
+    /// list sign ups waiting for approval
+    #[argh(switch)]
+    pending: bool,
+
+    /// approve the sign up with the given request id
+    #[argh(option, arg_name = "request_id")]
+    approve: Option<String>,
+
+    /// reject the sign up with the given request id
+    #[argh(option, arg_name = "request_id")]
+    reject: Option<String>,
+
     /// path to config directory. Defaults to "."
     #[argh(positional, default = "PathBuf::from(\".\")", greedy)]
     dir: PathBuf,
@@ -30,15 +49,62 @@ struct Args {
 async fn main() -> Result<()> {
     let args: Args = argh::from_env();
 
-    let _ = ctrlc::set_handler(move || {
-        println!("received Ctrl+C!");
-        exit(0)
-    });
-
     //Init Server:
     let mut server = Server::init_server(args.dir);
+
+    // The first signal starts draining, a second one stops at once
+    let drain = Duration::from_secs(server.drain_secs);
+    let _ = ctrlc::set_handler(move || {
+        if health::start_draining() {
+            exit(0)
+        }
+        tracing::info!(secs = drain.as_secs(), "draining before shutting down");
+        thread::spawn(move || {
+            thread::sleep(drain);
+            exit(0)
+        });
+    });
     if let Some(username) = args.name {
-        server.new_client(&username);
+        let created = if args.guest {
+            server.new_guest(&username)
+        } else {
+            server.new_client(&username, args.admin)
+        };
+        match created {
+            Ok(()) => {}
+            Err(UsernameError::Invalid) => eprintln!("Invalid Username {username}"),
+            Err(UsernameError::Taken) => eprintln!("Username {username} is Already Taken"),
+        }
+        return Ok(());
+    }
+    if args.pending {
+        for registration in server.get_registrations(Some(RegistrationStatus::Pending)) {
+            println!(
+                "{}\t{}\t{}\t{}",
+                registration.get_id(),
+                registration.username,
+                registration.ip,
+                registration.created_at
+            );
+        }
+        return Ok(());
+    }
+    for (request_id, approve) in [(args.approve, true), (args.reject, false)] {
+        let Some(request_id) = request_id else {
+            continue;
+        };
+        match server.decide_registration(&request_id, approve, CLI_ACTOR, None) {
+            Ok(registration) => {
+                println!("{} {}", registration.username, registration.status.as_str())
+            }
+            Err(DecideError::Unknown) => eprintln!("No Sign Up with Id {request_id}"),
+            Err(DecideError::AlreadyDecided) => {
+                eprintln!("Sign Up {request_id} was Already Decided")
+            }
+            Err(DecideError::UsernameTaken) => {
+                eprintln!("Username of Sign Up {request_id} is Already Taken")
+            }
+        }
         return Ok(());
     }
     let (mut server_side, client_side_generator) = interaction_channel(1);
@@ -51,6 +117,10 @@ async fn main() -> Result<()> {
         &mut server_side,
         Clients::Http,
     )));
+    let _metrics = tokio::spawn(metrics_main(client_side_generator(
+        &mut server_side,
+        Clients::Metrics,
+    )));
 
     while let Some(req) = server_side.recieve.recv().await {
         match req {
@@ -58,9 +128,13 @@ async fn main() -> Result<()> {
                 Clients::WebSocket,
                 ServerInteractions::WsSocket(server.get_addr_websocket()),
             ),
-            ClientInteractions::WsValidateClient(token) => server_side.respond(
+            ClientInteractions::WsAuthMethods => server_side.respond(
+                Clients::WebSocket,
+                ServerInteractions::WsAuthMethods(server.websocket_auth),
+            ),
+            ClientInteractions::WsValidateClient { ip, token } => server_side.respond(
                 Clients::WebSocket,
-                ServerInteractions::WsValidateClient(server.is_client_valid(token.as_str())),
+                ServerInteractions::WsValidateClient(server.is_client_valid(&token, ip)),
             ),
             ClientInteractions::WsClientConnected { addr, client } => {
                 server.client_connected(addr, client);
@@ -81,17 +155,48 @@ async fn main() -> Result<()> {
                 server.client_disconnected(&addr);
                 server_side.respond(Clients::WebSocket, ServerInteractions::WsClientLeft);
             }
+            ClientInteractions::WsSetPresence {
+                addr,
+                presence,
+                status_text,
+            } => server_side.respond(
+                Clients::WebSocket,
+                ServerInteractions::WsSetPresence(server.set_presence(
+                    &addr,
+                    presence,
+                    status_text,
+                )),
+            ),
+            ClientInteractions::WsStoreMessage {
+                message,
+                attachments,
+            } => server_side.respond(
+                Clients::WebSocket,
+                ServerInteractions::WsStoreMessage(server.store_message(message, attachments)),
+            ),
+            ClientInteractions::WsReact {
+                client_uuid,
+                message_uuid,
+                emoji,
+                added,
+            } => server_side.respond(
+                Clients::WebSocket,
+                ServerInteractions::WsReact(server.react(
+                    &client_uuid,
+                    &message_uuid,
+                    &emoji,
+                    added,
+                )),
+            ),
 
             ClientInteractions::HttpSocket => server_side.respond(
                 Clients::Http,
                 ServerInteractions::HttpSocket(server.get_addr_http()),
             ),
 
-            ClientInteractions::HttpValidateClient(token) => server_side.respond(
+            ClientInteractions::HttpValidateClient { ip, token } => server_side.respond(
                 Clients::Http,
-                ServerInteractions::HttpValidateClient(
-                    server.is_client_connected_by_token(token.as_str()),
-                ),
+                ServerInteractions::HttpValidateClient(server.is_client_valid(&token, ip)),
             ),
 
             ClientInteractions::HttpGetAllClients => server_side.respond(
@@ -102,6 +207,209 @@ async fn main() -> Result<()> {
                 Clients::Http,
                 ServerInteractions::HttpGetConnectedClients(server.get_connected_clients()),
             ),
+            ClientInteractions::HttpGetHistory {
+                thread,
+                before,
+                limit,
+            } => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpGetHistory(server.get_history(thread, before, limit)),
+            ),
+            ClientInteractions::HttpUploadLimit => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpUploadLimit(server.max_upload_size),
+            ),
+            ClientInteractions::HttpCheckDatabase => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpCheckDatabase(server.is_db_reachable()),
+            ),
+            ClientInteractions::HttpServerInfo => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpServerInfo(ServerInfo::new(&server)),
+            ),
+            ClientInteractions::HttpStoreAttachment {
+                owner_uuid,
+                file_name,
+                upload,
+            } => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpStoreAttachment(
+                    server.store_attachment(owner_uuid, &file_name, upload),
+                ),
+            ),
+            ClientInteractions::HttpGetAttachment {
+                attachment_id,
+                thumbnail,
+            } => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpGetAttachment(
+                    server.get_attachment(&attachment_id, thumbnail),
+                ),
+            ),
+            ClientInteractions::HttpSetAvatar { uuid, avatar } => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpSetAvatar(server.set_avatar(&uuid, avatar)),
+            ),
+            ClientInteractions::HttpGetAvatar(uuid) => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpGetAvatar(server.get_avatar(&uuid)),
+            ),
+            ClientInteractions::HttpRenameClient { uuid, username, ip } => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpRenameClient(server.rename_client(&uuid, &username, ip)),
+            ),
+            ClientInteractions::HttpGetUsernameHistory(uuid) => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpGetUsernameHistory(server.get_username_history(&uuid)),
+            ),
+            ClientInteractions::HttpExportAccount(uuid) => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpExportAccount(server.export_account(&uuid)),
+            ),
+            ClientInteractions::HttpDeleteAccount { uuid, ip } => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpDeleteAccount(server.delete_account(&uuid, ip)),
+            ),
+            ClientInteractions::HttpFindClient(username) => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpFindClient(server.find_client_by_username(&username)),
+            ),
+            ClientInteractions::HttpGetTokens(client_uuid) => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpGetTokens(server.get_tokens(&client_uuid)),
+            ),
+            ClientInteractions::HttpCreateToken {
+                client_uuid,
+                name,
+                scopes,
+                expires_at,
+                ip,
+            } => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpCreateToken(server.create_token(
+                    client_uuid,
+                    &name,
+                    scopes,
+                    expires_at,
+                    ip,
+                )),
+            ),
+            ClientInteractions::HttpRevokeToken {
+                client_uuid,
+                token_id,
+                ip,
+            } => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpRevokeToken(server.revoke_token(
+                    &client_uuid,
+                    &token_id,
+                    ip,
+                )),
+            ),
+            ClientInteractions::HttpCreateInvite {
+                created_by,
+                max_uses,
+                expires_at,
+                ip,
+            } => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpCreateInvite(
+                    server.create_invite(created_by, max_uses, expires_at, ip),
+                ),
+            ),
+            ClientInteractions::HttpGetInvites => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpGetInvites(server.get_invites()),
+            ),
+            ClientInteractions::HttpCreateGuest {
+                created_by,
+                username,
+                expires_at,
+                ip,
+            } => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpCreateGuest(server.create_guest(
+                    created_by,
+                    &username,
+                    expires_at,
+                    Some(ip),
+                )),
+            ),
+            ClientInteractions::HttpGetGuests => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpGetGuests(server.get_guests()),
+            ),
+            ClientInteractions::HttpRevokeGuest {
+                guest_id,
+                revoked_by,
+                ip,
+            } => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpRevokeGuest(server.revoke_guest(
+                    &guest_id,
+                    &revoked_by,
+                    ip,
+                )),
+            ),
+            ClientInteractions::HttpRevokeInvite {
+                invite_id,
+                revoked_by,
+                ip,
+            } => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpRevokeInvite(server.revoke_invite(
+                    &invite_id,
+                    &revoked_by,
+                    ip,
+                )),
+            ),
+            ClientInteractions::HttpRegister { ip, code, username } => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpRegister(server.register(ip, &code, &username)),
+            ),
+            ClientInteractions::HttpRequestRegistration { ip, username } => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpRequestRegistration(
+                    server.request_registration(ip, &username),
+                ),
+            ),
+            ClientInteractions::HttpGetRegistrations(status) => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpGetRegistrations(server.get_registrations(status)),
+            ),
+            ClientInteractions::HttpDecideRegistration {
+                request_id,
+                approve,
+                decided_by,
+                ip,
+            } => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpDecideRegistration(server.decide_registration(
+                    &request_id,
+                    approve,
+                    &decided_by,
+                    Some(ip),
+                )),
+            ),
+            ClientInteractions::HttpClaimRegistration { ip, claim_token } => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpClaimRegistration(
+                    server.claim_registration(ip, &claim_token),
+                ),
+            ),
+            ClientInteractions::HttpGetAuditLog(filter) => server_side.respond(
+                Clients::Http,
+                ServerInteractions::HttpGetAuditLog(server.get_audit_log(&filter)),
+            ),
+
+            ClientInteractions::MetricsSocket => server_side.respond(
+                Clients::Metrics,
+                ServerInteractions::MetricsSocket(server.get_metrics_addr()),
+            ),
+            ClientInteractions::MetricsServerStats => server_side.respond(
+                Clients::Metrics,
+                ServerInteractions::MetricsServerStats(server.get_server_stats()),
+            ),
         };
     }
     Ok(())
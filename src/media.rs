@@ -0,0 +1,412 @@
+// File Contains Processing of Uploaded Images
+
+use std::io::Cursor;
+
+use image::{
+    codecs::jpeg::JpegEncoder, imageops::FilterType, DynamicImage, ImageDecoder, ImageFormat,
+    ImageReader, Limits,
+};
+use tracing::{debug, error};
+
+pub const THUMBNAIL_SIZE: u32 = 320;
+pub const AVATAR_SIZE: u32 = 256;
+pub const AVATAR_UPLOAD_LIMIT: u64 = 4 * 1024 * 1024;
+const MAX_DIMENSION: u32 = 16384;
+const MAX_DECODE_ALLOC: u64 = 512 * 1024 * 1024;
+const JPEG_QUALITY: u8 = 80;
+
+const GPS_IFD_TAG: u16 = 0x8825;
+
+pub struct ImageInfo {
+    pub width: u32,
+    pub height: u32,
+    // None when the image already fits in a thumbnail
+    pub thumbnail: Option<Vec<u8>>,
+}
+
+pub struct Upload {
+    pub data: Vec<u8>,
+    pub content_type: String,
+    pub image: Option<ImageInfo>,
+}
+
+impl Upload {
+    // Sniffs images, strips their location data and renders a thumbnail.
+    // Decoding is CPU bound so this should run on a blocking thread.
+    pub fn process(mut data: Vec<u8>, content_type: String) -> Self {
+        let Some(format) = supported_format(&data) else {
+            // Claimed images that are not would otherwise be shown inline, svg included
+            let content_type = if content_type.starts_with("image/") {
+                "application/octet-stream".to_string()
+            } else {
+                content_type
+            };
+            return Self {
+                data,
+                content_type,
+                image: None,
+            };
+        };
+        match format {
+            ImageFormat::Jpeg => scrub_jpeg(&mut data),
+            ImageFormat::WebP => scrub_webp(&mut data),
+            ImageFormat::Png => data = strip_png(data),
+            _ => {}
+        }
+        let image = decode(&data, format)
+            .map_err(|e| debug!(error = %e, "failed to decode uploaded image"))
+            .ok()
+            .map(|image| ImageInfo {
+                width: image.width(),
+                height: image.height(),
+                thumbnail: (image.width() > THUMBNAIL_SIZE || image.height() > THUMBNAIL_SIZE)
+                    .then(|| encode(&image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)))
+                    .flatten(),
+            });
+        Self {
+            data,
+            content_type: format.to_mime_type().to_string(),
+            image,
+        }
+    }
+}
+
+// Centre square of the image scaled to AVATAR_SIZE, re-encoded so no metadata is kept.
+// None if the data is not a supported image.
+pub fn avatar(data: &[u8]) -> Option<Vec<u8>> {
+    let image = decode(data, supported_format(data)?)
+        .map_err(|e| debug!(error = %e, "failed to decode avatar"))
+        .ok()?;
+    let side = image.width().min(image.height());
+    let square = image
+        .crop_imm(
+            (image.width() - side) / 2,
+            (image.height() - side) / 2,
+            side,
+            side,
+        )
+        .resize_exact(AVATAR_SIZE, AVATAR_SIZE, FilterType::Lanczos3);
+    encode(&square)
+}
+
+fn supported_format(data: &[u8]) -> Option<ImageFormat> {
+    image::guess_format(data).ok().filter(|format| {
+        matches!(
+            format,
+            ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Gif | ImageFormat::WebP
+        )
+    })
+}
+
+// Decodes the first frame with its exif orientation applied
+fn decode(data: &[u8], format: ImageFormat) -> image::ImageResult<DynamicImage> {
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_DIMENSION);
+    limits.max_image_height = Some(MAX_DIMENSION);
+    limits.max_alloc = Some(MAX_DECODE_ALLOC);
+    let mut reader = ImageReader::with_format(Cursor::new(data), format);
+    reader.limits(limits);
+    let mut decoder = reader.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok(image)
+}
+
+// Jpeg for opaque images, png where transparency has to be kept
+fn encode(image: &DynamicImage) -> Option<Vec<u8>> {
+    let mut buf = Vec::new();
+    let result = if image.color().has_alpha() {
+        image.write_to(&mut Cursor::new(&mut buf), ImageFormat::Png)
+    } else {
+        JpegEncoder::new_with_quality(&mut buf, JPEG_QUALITY).encode_image(&image.to_rgb8())
+    };
+    result
+        .map_err(|e| error!(error = %e, "failed to encode image"))
+        .ok()
+        .map(|_| buf)
+}
+
+fn scrub_jpeg(data: &mut [u8]) {
+    let mut at = 2;
+    while let Some(&[0xFF, marker]) = data.get(at..at + 2) {
+        // Metadata segments all come before the image data
+        if marker == 0xDA || marker == 0xD9 {
+            break;
+        }
+        if marker == 0xFF {
+            at += 1;
+            continue;
+        }
+        let Some(&[high, low]) = data.get(at + 2..at + 4) else {
+            break;
+        };
+        let len = u16::from_be_bytes([high, low]) as usize;
+        if marker == 0xE1 {
+            if let Some(segment) = data.get_mut(at + 4..at + 2 + len) {
+                scrub_exif(segment);
+            }
+        }
+        at += 2 + len;
+    }
+}
+
+fn scrub_webp(data: &mut [u8]) {
+    if data.get(..4) != Some(b"RIFF") || data.get(8..12) != Some(b"WEBP") {
+        return;
+    }
+    let mut at = 12;
+    while let Some(&[a, b, c, d, s0, s1, s2, s3]) = data.get(at..at + 8) {
+        let len = u32::from_le_bytes([s0, s1, s2, s3]) as usize;
+        if &[a, b, c, d] == b"EXIF" {
+            if let Some(chunk) = data.get_mut(at + 8..at + 8 + len) {
+                scrub_exif(chunk);
+            }
+        }
+        // Chunks are padded to an even length
+        at += 8 + len + len % 2;
+    }
+}
+
+// Png has no orientation to keep, so the whole exif chunk is dropped
+fn strip_png(data: Vec<u8>) -> Vec<u8> {
+    let mut stripped = Vec::with_capacity(data.len());
+    stripped.extend_from_slice(&data[..8.min(data.len())]);
+    let mut at = 8;
+    while let Some(&[s0, s1, s2, s3, a, b, c, d]) = data.get(at..at + 8) {
+        let end = at + 12 + u32::from_be_bytes([s0, s1, s2, s3]) as usize;
+        let Some(chunk) = data.get(at..end) else {
+            // Keep whatever follows a truncated chunk for the decoder to reject
+            stripped.extend_from_slice(&data[at..]);
+            return stripped;
+        };
+        if &[a, b, c, d] != b"eXIf" {
+            stripped.extend_from_slice(chunk);
+        }
+        at = end;
+    }
+    stripped.extend_from_slice(data.get(at..).unwrap_or_default());
+    stripped
+}
+
+fn scrub_exif(exif: &mut [u8]) {
+    if exif.starts_with(b"Exif\0\0") {
+        scrub_gps(&mut exif[6..]);
+    } else {
+        scrub_gps(exif);
+    }
+}
+
+// Blanks the gps directory of a tiff structured exif block in place,
+// leaving an empty directory so offsets elsewhere stay valid
+fn scrub_gps(tiff: &mut [u8]) {
+    let big_endian = match tiff.get(..2) {
+        Some(b"MM") => true,
+        Some(b"II") => false,
+        _ => return,
+    };
+    let read_u16 = |tiff: &[u8], at: usize| {
+        let bytes = [*tiff.get(at)?, *tiff.get(at + 1)?];
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let read_u32 = |tiff: &[u8], at: usize| {
+        let bytes: [u8; 4] = tiff.get(at..at + 4)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        } as usize)
+    };
+
+    let Some(ifd) = read_u32(tiff, 4) else {
+        return;
+    };
+    let Some(entries) = read_u16(tiff, ifd) else {
+        return;
+    };
+    let Some(gps) = (0..entries as usize)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&entry| read_u16(tiff, entry) == Some(GPS_IFD_TAG))
+        .and_then(|entry| read_u32(tiff, entry + 8))
+    else {
+        return;
+    };
+    let Some(entries) = read_u16(tiff, gps) else {
+        return;
+    };
+    for entry in (0..entries as usize).map(|i| gps + 2 + i * 12) {
+        let (Some(kind), Some(count)) = (read_u16(tiff, entry + 2), read_u32(tiff, entry + 4))
+        else {
+            break;
+        };
+        let size = match kind {
+            1 | 2 | 6 | 7 => 1,
+            3 | 8 => 2,
+            4 | 9 | 11 => 4,
+            5 | 10 | 12 => 8,
+            _ => 0,
+        } * count;
+        // Values that do not fit in the entry are stored elsewhere
+        if size > 4 {
+            if let Some(offset) = read_u32(tiff, entry + 8) {
+                if let Some(value) = tiff.get_mut(offset..offset + size) {
+                    value.fill(0);
+                }
+            }
+        }
+        if let Some(entry) = tiff.get_mut(entry..entry + 12) {
+            entry.fill(0);
+        }
+    }
+    if let Some(count) = tiff.get_mut(gps..gps + 2) {
+        count.fill(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Where the gps directory and the latitude it points to sit in `tiff`
+    const GPS_START: usize = 26;
+    const GPS_END: usize = 80;
+
+    // Exif with a gps directory holding a latitude reference and a latitude
+    // stored outside its entry
+    fn tiff(big_endian: bool) -> Vec<u8> {
+        let u16 = |v: u16| {
+            if big_endian {
+                v.to_be_bytes()
+            } else {
+                v.to_le_bytes()
+            }
+        };
+        let u32 = |v: u32| {
+            if big_endian {
+                v.to_be_bytes()
+            } else {
+                v.to_le_bytes()
+            }
+        };
+        let mut tiff = vec![];
+        tiff.extend(if big_endian { b"MM" } else { b"II" });
+        tiff.extend(u16(42));
+        tiff.extend(u32(8));
+        // First directory, only pointing to the gps one
+        tiff.extend(u16(1));
+        tiff.extend(u16(GPS_IFD_TAG));
+        tiff.extend(u16(4));
+        tiff.extend(u32(1));
+        tiff.extend(u32(GPS_START as u32));
+        tiff.extend(u32(0));
+        assert_eq!(tiff.len(), GPS_START);
+        tiff.extend(u16(2));
+        tiff.extend(u16(1));
+        tiff.extend(u16(2));
+        tiff.extend(u32(2));
+        tiff.extend(b"N\0\0\0");
+        tiff.extend(u16(2));
+        tiff.extend(u16(5));
+        tiff.extend(u32(3));
+        tiff.extend(u32(56));
+        tiff.extend(u32(0));
+        tiff.extend([0x2A; 24]);
+        assert_eq!(tiff.len(), GPS_END);
+        tiff
+    }
+
+    fn jpeg(tiff: &[u8]) -> Vec<u8> {
+        let mut data = vec![0xFF, 0xD8, 0xFF, 0xE1];
+        data.extend((2 + 6 + tiff.len() as u16).to_be_bytes());
+        data.extend(b"Exif\0\0");
+        data.extend(tiff);
+        data.extend([0xFF, 0xDA, 0x00, 0x02, 0xFF, 0xD9]);
+        data
+    }
+
+    fn webp(tiff: &[u8]) -> Vec<u8> {
+        let mut data = b"RIFF".to_vec();
+        data.extend((4 + 8 + tiff.len() as u32).to_le_bytes());
+        data.extend(b"WEBPEXIF");
+        data.extend((tiff.len() as u32).to_le_bytes());
+        data.extend(tiff);
+        data
+    }
+
+    fn png_chunk(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut chunk = (body.len() as u32).to_be_bytes().to_vec();
+        chunk.extend(kind);
+        chunk.extend(body);
+        chunk.extend([0; 4]);
+        chunk
+    }
+
+    fn png(tiff: &[u8]) -> Vec<u8> {
+        let mut data = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+        data.extend(png_chunk(b"IHDR", &[0; 13]));
+        data.extend(png_chunk(b"eXIf", tiff));
+        data.extend(png_chunk(b"IEND", &[]));
+        data
+    }
+
+    fn has_exif_chunk(png: &[u8]) -> bool {
+        png.windows(4).any(|window| window == b"eXIf")
+    }
+
+    #[test]
+    fn gps_is_blanked() {
+        for big_endian in [true, false] {
+            let tiff = tiff(big_endian);
+
+            let mut data = jpeg(&tiff);
+            scrub_jpeg(&mut data);
+            assert!(data[12 + GPS_START..12 + GPS_END].iter().all(|&b| b == 0));
+            // The first directory still points to the now empty gps one
+            assert_eq!(data[12..12 + GPS_START], tiff[..GPS_START]);
+
+            let mut data = webp(&tiff);
+            scrub_webp(&mut data);
+            assert!(data[20 + GPS_START..20 + GPS_END].iter().all(|&b| b == 0));
+
+            let stripped = strip_png(png(&tiff));
+            assert!(!has_exif_chunk(&stripped));
+            assert_eq!(stripped.len(), 8 + 25 + 12);
+        }
+    }
+
+    #[test]
+    fn truncated_files_do_not_panic() {
+        let tiff = tiff(false);
+        for data in [jpeg(&tiff), webp(&tiff), png(&tiff)] {
+            for len in 0..=data.len() {
+                let mut truncated = data[..len].to_vec();
+                scrub_jpeg(&mut truncated);
+                scrub_webp(&mut truncated);
+                scrub_exif(&mut truncated);
+                strip_png(truncated);
+            }
+        }
+    }
+
+    // Every byte in turn is set to values that make lengths, counts and offsets huge
+    #[test]
+    fn malformed_files_do_not_panic() {
+        let tiff = tiff(true);
+        for data in [jpeg(&tiff), webp(&tiff), png(&tiff)] {
+            for at in 0..data.len() {
+                for value in [0x00, 0x7F, 0xFF] {
+                    let mut malformed = data.clone();
+                    malformed[at] = value;
+                    scrub_jpeg(&mut malformed);
+                    scrub_webp(&mut malformed);
+                    scrub_exif(&mut malformed);
+                    strip_png(malformed);
+                }
+            }
+        }
+    }
+}
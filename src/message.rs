@@ -1,10 +1,22 @@
-use std::{sync::Arc, time::{SystemTime, UNIX_EPOCH}};
+use std::{collections::HashSet, sync::Arc, time::{SystemTime, UNIX_EPOCH}};
 
+use lazy_static::lazy_static;
 use rand::distributions::{Alphanumeric, DistString};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_repr::Deserialize_repr;
-use tokio_tungstenite::tungstenite::protocol::Message as Message_Tungestenite;
+use sqlite::{Connection, Row, Value};
+
+use crate::{attachment::{Attachment, ATTACHMENTS_PER_MESSAGE}, client::Presence, reaction::Reaction};
+
+pub const STATUS_TEXT_LIMIT: usize = 128;
+
+lazy_static! {
+    // Client uuids are either "xxx-xxx-xxx-xxx-" (older clients) or hyphenated hex
+    static ref MENTION: Regex = Regex::new(
+        r"<<!([[:alnum:]]{3}-[[:alnum:]]{3}-[[:alnum:]]{3}-[[:alnum:]]{3}-|[[:xdigit:]]{8}-[[:xdigit:]]{4}-[[:xdigit:]]{4}-[[:xdigit:]]{4}-[[:xdigit:]]{12})>>"
+    ).unwrap();
+}
 
 //Server Response to Peers
 #[derive(Clone, Debug, Serialize)]
@@ -16,6 +28,19 @@ pub struct Message {
     pub is_mentioned: bool,
     unix_time: u64,
     is_server_message: bool,
+    // Sent by a guest account, which may no longer exist
+    pub is_guest_message: bool,
+    pub parent_uuid: Option<Arc<str>>,
+    pub in_thread: bool,
+    pub reply_count: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<Reaction>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    #[serde(skip)]
+    pub mention_parent: bool,
+    #[serde(skip)]
+    pub parent_author_uuid: Option<Arc<str>>,
 }
 
 impl Message {
@@ -23,6 +48,10 @@ impl Message {
         Arc::from(Alphanumeric.sample_string(&mut rand::thread_rng(), 16))
     }
 
+    pub fn regenerate_uuid(&mut self) {
+        self.message_uuid = Self::generate_message_id();
+    }
+
     pub fn new(data: String, author_uuid: Arc<str>) -> Self {
         Self {
             message_uuid: Self::generate_message_id(),
@@ -31,10 +60,31 @@ impl Message {
             edited: false,
             is_mentioned: false,
             unix_time: SystemTime::now().duration_since(UNIX_EPOCH).expect("Time Travel?").as_secs(),
-            is_server_message: false
+            is_server_message: false,
+            is_guest_message: false,
+            parent_uuid: None,
+            in_thread: false,
+            reply_count: 0,
+            reactions: vec![],
+            attachments: vec![],
+            mention_parent: false,
+            parent_author_uuid: None,
         }
     }
 
+    // Inline reply, or the start of a thread under the parent when in_thread is set
+    pub fn reply_to(
+        mut self,
+        parent_uuid: Arc<str>,
+        in_thread: bool,
+        mention_parent: bool,
+    ) -> Self {
+        self.parent_uuid = Some(parent_uuid);
+        self.in_thread = in_thread;
+        self.mention_parent = mention_parent;
+        self
+    }
+
      pub fn new_server_message(data: String) -> Self {
         Self {
             message_uuid: Self::generate_message_id(),
@@ -44,16 +94,73 @@ impl Message {
             is_mentioned: false,
             unix_time: SystemTime::now().duration_since(UNIX_EPOCH).expect("Time Travel?").as_secs(),
             is_server_message: true,
+            is_guest_message: false,
+            parent_uuid: None,
+            in_thread: false,
+            reply_count: 0,
+            reactions: vec![],
+            attachments: vec![],
+            mention_parent: false,
+            parent_author_uuid: None,
+        }
+    }
+
+    pub fn from_db_row(row: Row) -> Self {
+        Self {
+            message_uuid: row.read::<&str, _>("message_uuid").into(),
+            author_uuid: row.read::<&str, _>("author_uuid").into(),
+            data: row.read::<&str, _>("data").into(),
+            edited: row.read::<i64, _>("edited") != 0,
+            is_mentioned: false,
+            unix_time: row.read::<i64, _>("unix_time") as u64,
+            is_server_message: row.read::<i64, _>("is_server_message") != 0,
+            is_guest_message: row.read::<i64, _>("is_guest_message") != 0,
+            parent_uuid: row.read::<Option<&str>, _>("parent_uuid").map(Arc::from),
+            in_thread: row.read::<i64, _>("in_thread") != 0,
+            reply_count: row.read::<i64, _>("reply_count") as u64,
+            reactions: vec![],
+            attachments: vec![],
+            mention_parent: false,
+            parent_author_uuid: None,
         }
     }
 
+    pub fn write_to_db(&self, connection: &Connection) {
+        let query = "INSERT INTO messages (message_uuid, author_uuid, data, edited, unix_time, is_server_message, parent_uuid, in_thread, reply_count, is_guest_message) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)";
+        let mut statement = connection.prepare(query).unwrap();
+        statement
+            .bind_iter::<_, (_, Value)>([
+                (1, self.message_uuid.as_ref().into()),
+                (2, self.author_uuid.as_ref().into()),
+                (3, self.data.as_str().into()),
+                (4, (self.edited as i64).into()),
+                (5, (self.unix_time as i64).into()),
+                (6, (self.is_server_message as i64).into()),
+                (7, self.parent_uuid.as_deref().into()),
+                (8, (self.in_thread as i64).into()),
+                (9, (self.reply_count as i64).into()),
+                (10, (self.is_guest_message as i64).into()),
+            ])
+            .unwrap();
+        let _ = statement.next();
+    }
+
+    pub fn get_uuid(&self) -> Arc<str> {
+        self.message_uuid.clone()
+    }
+
+    pub fn get_author_uuid(&self) -> Arc<str> {
+        self.author_uuid.clone()
+    }
 
     pub fn mentions(&self) -> Vec<String> {
-        let re = Regex::new(r"<<!(.{16})>>").unwrap();
         let mut mentions = vec![];
-        for (_, [uuid]) in re.captures_iter(&self.data).map(|c| c.extract()) {
+        for (_, [uuid]) in MENTION.captures_iter(&self.data).map(|c| c.extract()) {
             mentions.push(uuid.to_string());
         }
+        if let Some(author) = &self.parent_author_uuid {
+            mentions.push(author.to_string());
+        }
         mentions
     }
 
@@ -63,8 +170,81 @@ impl Message {
         new_message
     }
 
-    pub fn to_message(&self) -> Message_Tungestenite{
-        Message_Tungestenite::from(serde_json::to_string(self).unwrap())
+    pub fn to_payload(&self) -> Arc<str> {
+        Arc::from(serde_json::to_string(self).unwrap())
+    }
+}
+
+// Message serialized once for fan-out to every connected peer.
+// Recipients share the same payload, mentioned recipients share a second one.
+// Only serializing is shared, each session still copies its payload into a frame.
+#[derive(Clone, Debug)]
+pub struct Broadcast {
+    payload: Arc<str>,
+    mentioned_payload: Option<Arc<str>>,
+    mentions: HashSet<String>,
+}
+
+impl Broadcast {
+    pub fn new(message: &Message) -> Self {
+        let mentions = message.mentions().into_iter().collect::<HashSet<_>>();
+        let mentioned_payload = if mentions.is_empty() {
+            None
+        } else {
+            Some(message.set_mention().to_payload())
+        };
+        Self {
+            payload: message.to_payload(),
+            mentioned_payload,
+            mentions,
+        }
+    }
+
+    pub fn payload_for(&self, uuid: &str) -> Arc<str> {
+        match &self.mentioned_payload {
+            Some(payload) if self.mentions.contains(uuid) => payload.clone(),
+            _ => self.payload.clone(),
+        }
+    }
+}
+
+// Ephemeral events relayed to peers, never stored
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    Typing {
+        author_uuid: Arc<str>,
+        typing: bool,
+        expires_in: u64,
+    },
+    Presence {
+        uuid: Arc<str>,
+        presence: Presence,
+        status_text: Option<String>,
+        last_seen: u64,
+    },
+    Reaction {
+        message_uuid: Arc<str>,
+        user_uuid: Arc<str>,
+        added: bool,
+        reaction: Reaction,
+    },
+    Profile {
+        uuid: Arc<str>,
+        username: String,
+        display_name: String,
+        about_me: String,
+        avatar: Option<Arc<str>>,
+    },
+    // Acknowledges a token sent as the first message
+    Authenticated {
+        uuid: Arc<str>,
+    },
+}
+
+impl Event {
+    pub fn to_payload(&self) -> Arc<str> {
+        Arc::from(serde_json::to_string(self).unwrap())
     }
 }
 
@@ -74,15 +254,32 @@ pub enum MessageOps {
     NewMessage,
     EditMessage,
     DeleteMessage,
+    Typing,
+    SetPresence,
+    AddReaction,
+    RemoveReaction,
 }
 
 //Client Message recieve
 #[derive(Debug, Clone, Deserialize)]
 pub struct ClientSend {
     pub op: MessageOps,
+    #[serde(default)]
     pub allowed_mentions: bool,
     message_uuid: Option<String>,
+    #[serde(default)]
     pub message: String,
+    pub typing: Option<bool>,
+    pub presence: Option<Presence>,
+    pub status_text: Option<String>,
+    pub emoji: Option<String>,
+    parent_uuid: Option<String>,
+    #[serde(default)]
+    pub thread: bool,
+    #[serde(default)]
+    pub mention_parent: bool,
+    #[serde(default)]
+    pub attachments: Vec<String>,
 }
 
 impl ClientSend {
@@ -91,7 +288,13 @@ impl ClientSend {
         if let Ok(mut k) = serde_json::from_slice::<Self>(data.as_slice()) {
             k.parse_message_uuid();
             match k.op {
-                MessageOps::NewMessage => Some(k),
+                MessageOps::NewMessage => {
+                    if k.attachments.len() > ATTACHMENTS_PER_MESSAGE {
+                        None
+                    } else {
+                        Some(k)
+                    }
+                }
                 MessageOps::EditMessage | MessageOps::DeleteMessage => {
                     if k.message_uuid.is_some() {
                         Some(k)
@@ -99,18 +302,64 @@ impl ClientSend {
                         None
                     }
                 }
+                MessageOps::AddReaction | MessageOps::RemoveReaction => {
+                    if k.message_uuid.is_some()
+                        && k.emoji.as_deref().is_some_and(Reaction::is_valid_emoji)
+                    {
+                        Some(k)
+                    } else {
+                        None
+                    }
+                }
+                MessageOps::Typing => {
+                    if k.typing.is_some() {
+                        Some(k)
+                    } else {
+                        None
+                    }
+                }
+                MessageOps::SetPresence => match k.presence {
+                    Some(Presence::Offline) | None => None,
+                    Some(_) => {
+                        k.status_text = k
+                            .status_text
+                            .map(|text| text.chars().take(STATUS_TEXT_LIMIT).collect::<String>())
+                            .filter(|text| !text.is_empty());
+                        Some(k)
+                    }
+                },
             }
         } else {
             None
         }
     }
 
+    pub fn get_message_uuid(&self) -> Option<String> {
+        self.message_uuid.clone()
+    }
+
+    pub fn get_parent_uuid(&self) -> Option<String> {
+        self.parent_uuid.clone()
+    }
+
     pub fn parse_message_uuid(&mut self) {
         match self.op {
             MessageOps::NewMessage => {
                 self.message_uuid = None;
+                // A malformed parent matches no message, so storing it fails like for an
+                // unknown parent and only this message is dropped
+                self.parent_uuid = self
+                    .parent_uuid
+                    .as_ref()
+                    .map(|uuid| uuid.chars().take(16).collect::<String>());
             }
-            MessageOps::EditMessage | MessageOps::DeleteMessage => {
+            MessageOps::Typing | MessageOps::SetPresence => {
+                self.message_uuid = None;
+            }
+            MessageOps::EditMessage
+            | MessageOps::DeleteMessage
+            | MessageOps::AddReaction
+            | MessageOps::RemoveReaction => {
                 if let Some(uuid) = self
                     .message_uuid
                     .as_ref()
@@ -126,3 +375,44 @@ impl ClientSend {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(data: String) -> Message {
+        Message::new(data, "000-000-000-000-".into())
+    }
+
+    #[test]
+    fn mentions_in_both_uuid_formats() {
+        let old = "a1b-2c3-d4e-5f6-";
+        let new = "3f2504e0-4f89-41d3-9a0c-0305e82c3301";
+        let mentions = message(format!("hi <<!{old}>> and <<!{new}>>")).mentions();
+        assert_eq!(mentions, vec![old.to_string(), new.to_string()]);
+    }
+
+    #[test]
+    fn malformed_mentions_are_ignored() {
+        for data in [
+            "<<!a1b-2c3-d4e->>",
+            "<<!a1b-2c3-d4e-5f6>>",
+            "<<!a1b-2c3-d4e-5f6-7g8->>",
+            "<<!a_b-2c3-d4e-5f6->>",
+            "<<!3f2504e0-4f89-41d3-9a0c-0305e82c330>>",
+            "<<!3f2504e0-4f89-41d3-9a0c-0305e82c33011>>",
+            "<<!3g2504e0-4f89-41d3-9a0c-0305e82c3301>>",
+            "<<!3f2504e04f8941d39a0c0305e82c3301>>",
+            "<<3f2504e0-4f89-41d3-9a0c-0305e82c3301>>",
+            "<<!3f2504e0-4f89-41d3-9a0c-0305e82c3301>",
+        ] {
+            assert!(message(data.to_string()).mentions().is_empty(), "{data}");
+        }
+    }
+
+    #[test]
+    fn malformed_parent_is_kept_for_storing_to_refuse() {
+        let parsed = ClientSend::parse(br#"{"op": 0, "message": "hi", "parent_uuid": "abc"}"#.to_vec());
+        assert_eq!(parsed.unwrap().get_parent_uuid().as_deref(), Some("abc"));
+    }
+}
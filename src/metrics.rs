@@ -0,0 +1,278 @@
+// File Contains the Prometheus Metrics of the Server
+//
+// Counters and histograms are global and updated where things happen, what the
+// server holds (sessions, their queues) is asked for at scrape time. They are
+// served on `metrics_addr`, apart from the public HTTP server and without
+// authentication, so bind it to an address only the scraper can reach.
+
+use std::{
+    collections::BTreeMap,
+    ffi::{c_int, c_uint, c_void},
+    fmt::Write,
+    ptr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use http_body_util::Full;
+use hyper::{
+    body::{Bytes, Incoming},
+    header::CONTENT_TYPE,
+    server::conn::http1::Builder,
+    service::service_fn,
+    Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use sqlite::Connection;
+use tokio::{net::TcpListener, sync::Mutex};
+use tracing::info;
+
+use crate::{
+    channel::{ClientChannel, ClientInteractions},
+    lockout::AuthError,
+};
+
+pub static METRICS: Metrics = Metrics::new();
+
+// Upper bounds in seconds, from a tenth of a millisecond to a second
+const BUCKETS: [f64; 13] = [
+    0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0,
+];
+
+pub struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_nanos: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|bound| secs <= *bound) {
+            self.buckets[i].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    // Buckets are cumulative in the exposition format
+    fn write(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} histogram");
+        let mut cumulative = 0;
+        for (bound, bucket) in BUCKETS.iter().zip(self.buckets.iter()) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum {sum}\n{name}_count {count}");
+    }
+}
+
+pub struct Metrics {
+    messages: AtomicU64,
+    auth_invalid: AtomicU64,
+    auth_throttled: AtomicU64,
+    http_responses: StdMutex<BTreeMap<u16, u64>>,
+    pub broadcast: Histogram,
+    pub db_query: Histogram,
+}
+
+// What the server holds at scrape time
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ServerStats {
+    pub connected_clients: usize,
+    pub sessions: usize,
+    // Payloads queued for sessions and not yet written to their sockets
+    pub queued_payloads: usize,
+    pub max_queued_payloads: usize,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            messages: AtomicU64::new(0),
+            auth_invalid: AtomicU64::new(0),
+            auth_throttled: AtomicU64::new(0),
+            http_responses: StdMutex::new(BTreeMap::new()),
+            broadcast: Histogram::new(),
+            db_query: Histogram::new(),
+        }
+    }
+
+    pub fn message_stored(&self) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn auth_failed(&self, err: AuthError) {
+        let counter = match err {
+            AuthError::Invalid => &self.auth_invalid,
+            AuthError::Throttled(_) => &self.auth_throttled,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn http_response(&self, status: StatusCode) {
+        *self
+            .http_responses
+            .lock()
+            .unwrap()
+            .entry(status.as_u16())
+            .or_default() += 1;
+    }
+
+    // Prometheus text exposition format, version 0.0.4
+    pub fn render(&self, stats: ServerStats) -> String {
+        let mut out = String::new();
+        let gauges = [
+            (
+                "tensor_connected_clients",
+                "Clients with at least one open websocket session.",
+                stats.connected_clients,
+            ),
+            (
+                "tensor_websocket_sessions",
+                "Open websocket sessions.",
+                stats.sessions,
+            ),
+            (
+                "tensor_queued_payloads",
+                "Payloads waiting to be written to websocket sessions.",
+                stats.queued_payloads,
+            ),
+            (
+                "tensor_max_queued_payloads",
+                "Payloads waiting for the most backed up websocket session.",
+                stats.max_queued_payloads,
+            ),
+        ];
+        for (name, help, value) in gauges {
+            let _ = writeln!(
+                out,
+                "# HELP {name} {help}\n# TYPE {name} gauge\n{name} {value}"
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP tensor_messages_total Messages stored, rate() gives messages per second.\n# TYPE tensor_messages_total counter\ntensor_messages_total {}",
+            self.messages.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP tensor_auth_failures_total Rejected token validations.\n# TYPE tensor_auth_failures_total counter\ntensor_auth_failures_total{{reason=\"invalid\"}} {}\ntensor_auth_failures_total{{reason=\"throttled\"}} {}",
+            self.auth_invalid.load(Ordering::Relaxed),
+            self.auth_throttled.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "# HELP tensor_http_responses_total HTTP responses by status code.\n# TYPE tensor_http_responses_total counter"
+        );
+        for (status, count) in self.http_responses.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "tensor_http_responses_total{{status=\"{status}\"}} {count}"
+            );
+        }
+        self.broadcast.write(
+            &mut out,
+            "tensor_broadcast_duration_seconds",
+            "Time to queue an event for every recipient session.",
+        );
+        self.db_query.write(
+            &mut out,
+            "tensor_db_query_duration_seconds",
+            "Run time of database statements.",
+        );
+        out
+    }
+}
+
+// Times every statement run on the connection
+pub fn time_queries(connection: &Connection) {
+    // The callback keeps no state, so there is nothing to pass it
+    unsafe {
+        sqlite3_sys::sqlite3_trace_v2(
+            connection.as_raw(),
+            sqlite3_sys::SQLITE_TRACE_PROFILE as c_uint,
+            Some(on_profile),
+            ptr::null_mut(),
+        );
+    }
+}
+
+extern "C" fn on_profile(
+    event: c_uint,
+    _: *mut c_void,
+    _: *mut c_void,
+    nanos: *mut c_void,
+) -> c_int {
+    if event == sqlite3_sys::SQLITE_TRACE_PROFILE as c_uint && !nanos.is_null() {
+        // Profile events point to the run time of the statement in nanoseconds
+        let nanos = unsafe { *(nanos as *const i64) };
+        METRICS
+            .db_query
+            .observe(Duration::from_nanos(nanos.max(0) as u64));
+    }
+    0
+}
+
+async fn handle_scrape(
+    req: Request<Incoming>,
+    client_channel: Arc<Mutex<ClientChannel>>,
+) -> Result<Response<Full<Bytes>>, hyper::Error> {
+    if req.method() != Method::GET || req.uri().path() != "/metrics" {
+        let mut res = Response::new(Full::new(Bytes::from("NOT FOUND\n")));
+        *res.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(res);
+    }
+    let stats = client_channel
+        .lock()
+        .await
+        .request(ClientInteractions::MetricsServerStats)
+        .await
+        .server_stats();
+    Ok(Response::builder()
+        .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+        .body(Full::new(Bytes::from(METRICS.render(stats))))
+        .unwrap())
+}
+
+pub async fn metrics_main(mut client: ClientChannel) -> Result<()> {
+    let Some(addr) = client
+        .request(ClientInteractions::MetricsSocket)
+        .await
+        .socket_addr()
+    else {
+        return Ok(());
+    };
+    let listener = TcpListener::bind(&addr).await.expect("Failed to bind");
+    info!(%addr, "listening for metrics scrapes");
+
+    let client_channel = Arc::new(Mutex::new(client));
+    while let Ok((stream, _)) = listener.accept().await {
+        let client_channel = client_channel.clone();
+        tokio::spawn(async move {
+            let _ = Builder::new()
+                .serve_connection(
+                    TokioIo::new(stream),
+                    service_fn(|req| handle_scrape(req, client_channel.clone())),
+                )
+                .await;
+        });
+    }
+    Ok(())
+}
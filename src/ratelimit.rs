@@ -0,0 +1,36 @@
+// Token bucket for limiting how often a connection may perform an action
+
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    capacity: f64,
+    tokens: f64,
+    refill_per_sec: f64,
+    last_refill: Instant,
+}
+
+impl RateLimit {
+    // Allows `burst` actions at once, refilled evenly over `period`
+    pub fn new(burst: u32, period: Duration) -> Self {
+        Self {
+            capacity: burst as f64,
+            tokens: burst as f64,
+            refill_per_sec: burst as f64 / period.as_secs_f64(),
+            last_refill: Instant::now(),
+        }
+    }
+
+    pub fn try_acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
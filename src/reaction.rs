@@ -0,0 +1,131 @@
+// File Contains Structs for Message Reactions and their Storage
+
+use std::{collections::HashMap, sync::Arc};
+
+use serde::Serialize;
+use sqlite::{Connection, Value};
+
+use crate::server::unix_time;
+
+pub const EMOJI_LIMIT: usize = 32;
+
+// All reactions with one emoji on one message
+#[derive(Clone, Debug, Serialize)]
+pub struct Reaction {
+    pub emoji: String,
+    pub count: usize,
+    pub users: Vec<Arc<str>>,
+}
+
+impl Reaction {
+    pub fn is_valid_emoji(emoji: &str) -> bool {
+        !emoji.is_empty()
+            && emoji.chars().count() <= EMOJI_LIMIT
+            && !emoji.chars().any(char::is_whitespace)
+    }
+
+    // Returns whether the reaction was not already present
+    pub fn add(
+        connection: &Connection,
+        message_uuid: &str,
+        client_uuid: &str,
+        emoji: &str,
+    ) -> bool {
+        let query = "INSERT OR IGNORE INTO reactions (message_uuid, client_uuid, emoji, unix_time) VALUES (?, ?, ?, ?)";
+        let mut statement = connection.prepare(query).unwrap();
+        statement
+            .bind_iter::<_, (_, Value)>([
+                (1, message_uuid.into()),
+                (2, client_uuid.into()),
+                (3, emoji.into()),
+                (4, (unix_time() as i64).into()),
+            ])
+            .unwrap();
+        let _ = statement.next();
+        connection.change_count() > 0
+    }
+
+    // Returns whether the reaction was present
+    pub fn remove(
+        connection: &Connection,
+        message_uuid: &str,
+        client_uuid: &str,
+        emoji: &str,
+    ) -> bool {
+        let query =
+            "DELETE FROM reactions WHERE message_uuid = ? AND client_uuid = ? AND emoji = ?";
+        let mut statement = connection.prepare(query).unwrap();
+        statement
+            .bind_iter::<_, (_, Value)>([
+                (1, message_uuid.into()),
+                (2, client_uuid.into()),
+                (3, emoji.into()),
+            ])
+            .unwrap();
+        let _ = statement.next();
+        connection.change_count() > 0
+    }
+
+    pub fn for_emoji(connection: &Connection, message_uuid: &str, emoji: &str) -> Self {
+        let query = "SELECT client_uuid FROM reactions WHERE message_uuid = ? AND emoji = ? ORDER BY unix_time";
+        let users = connection
+            .prepare(query)
+            .unwrap()
+            .into_iter()
+            .bind_iter::<_, (_, Value)>([(1, message_uuid.into()), (2, emoji.into())])
+            .unwrap()
+            .map(|row| Arc::from(row.unwrap().read::<&str, _>("client_uuid")))
+            .collect::<Vec<_>>();
+        Self {
+            emoji: emoji.to_string(),
+            count: users.len(),
+            users,
+        }
+    }
+
+    // Reactions grouped by message, emojis in the order they were first used
+    pub fn for_messages(
+        connection: &Connection,
+        message_uuids: &[Arc<str>],
+    ) -> HashMap<Arc<str>, Vec<Self>> {
+        let mut reactions: HashMap<Arc<str>, Vec<Self>> = HashMap::new();
+        if message_uuids.is_empty() {
+            return reactions;
+        }
+        let query = format!(
+            "SELECT message_uuid, client_uuid, emoji FROM reactions WHERE message_uuid IN ({}) ORDER BY unix_time",
+            vec!["?"; message_uuids.len()].join(", ")
+        );
+        let rows = connection
+            .prepare(query)
+            .unwrap()
+            .into_iter()
+            .bind_iter::<_, (_, Value)>(
+                message_uuids
+                    .iter()
+                    .enumerate()
+                    .map(|(i, uuid)| (i + 1, uuid.as_ref().into())),
+            )
+            .unwrap();
+        for row in rows {
+            let row = row.unwrap();
+            let message = reactions
+                .entry(row.read::<&str, _>("message_uuid").into())
+                .or_default();
+            let emoji = row.read::<&str, _>("emoji");
+            let user = Arc::from(row.read::<&str, _>("client_uuid"));
+            match message.iter_mut().find(|r| r.emoji == emoji) {
+                Some(reaction) => {
+                    reaction.count += 1;
+                    reaction.users.push(user);
+                }
+                None => message.push(Self {
+                    emoji: emoji.to_string(),
+                    count: 1,
+                    users: vec![user],
+                }),
+            }
+        }
+        reactions
+    }
+}
@@ -0,0 +1,247 @@
+// File Contains the Queue of Sign Ups Waiting for an Admin's Approval
+//
+// A sign up gets a claim token ("tensorclaim_<request id>.<secret>") which the
+// applicant uses to fetch their client config once approved. The client token
+// itself is only generated when claimed, so it is never stored in plain.
+
+use std::{net::IpAddr, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use sqlite::{Connection, Row, Value};
+
+use crate::{
+    server::unix_time,
+    token::{self, TokenKey},
+};
+
+pub const CLAIM_PREFIX: &str = "tensorclaim";
+pub const MAX_PENDING: usize = 100;
+pub const MAX_PENDING_PER_ADDRESS: usize = 3;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationMode {
+    // Only invite codes create clients
+    #[default]
+    Invite,
+    // Anyone may sign up, an admin approves or rejects each request.
+    // Invite codes keep working.
+    Approval,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    InvalidInvite,
+    InvalidUsername,
+    UsernameTaken,
+    // Seconds until the address may try again
+    Throttled(u64),
+    // Sign ups without an invite are not accepted
+    Closed,
+    QueueFull,
+    UnknownClaim,
+    Pending,
+    Rejected,
+    AlreadyClaimed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecideError {
+    Unknown,
+    AlreadyDecided,
+    // Someone else got the name since the sign up was queued
+    UsernameTaken,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationStatus {
+    Pending,
+    Approved,
+    Rejected,
+    // Approved and the applicant has fetched their config
+    Claimed,
+}
+
+impl RegistrationStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Approved => "approved",
+            Self::Rejected => "rejected",
+            Self::Claimed => "claimed",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "pending" => Some(Self::Pending),
+            "approved" => Some(Self::Approved),
+            "rejected" => Some(Self::Rejected),
+            "claimed" => Some(Self::Claimed),
+            _ => None,
+        }
+    }
+
+    pub fn from_db(value: &str) -> Self {
+        Self::parse(value).unwrap_or(Self::Pending)
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Registration {
+    request_id: Arc<str>,
+    #[serde(skip)]
+    claim_hash: Arc<str>,
+    pub username: String,
+    pub status: RegistrationStatus,
+    pub client_uuid: Option<Arc<str>>,
+    pub ip: String,
+    pub created_at: u64,
+    pub decided_at: Option<u64>,
+    // Uuid of the admin, "cli" when decided on the server host
+    pub decided_by: Option<String>,
+}
+
+impl Registration {
+    // The claim token is only ever known here, only its hash is stored
+    pub fn new(
+        connection: &Connection,
+        key: &TokenKey,
+        username: &str,
+        ip: IpAddr,
+    ) -> (Self, Arc<str>) {
+        let mut request_id = token::generate_token_id();
+        while Self::get(connection, &request_id).is_some() {
+            request_id = token::generate_token_id();
+        }
+        let claim_token = token::generate(Some(CLAIM_PREFIX), &request_id);
+        let s = Self {
+            request_id,
+            claim_hash: key.hash(&claim_token).into(),
+            username: username.to_string(),
+            status: RegistrationStatus::Pending,
+            client_uuid: None,
+            ip: ip.to_string(),
+            created_at: unix_time(),
+            decided_at: None,
+            decided_by: None,
+        };
+        s.write_to_db(connection);
+        (s, claim_token)
+    }
+
+    pub fn from_db_row(row: Row) -> Self {
+        Self {
+            request_id: row.read::<&str, _>("request_id").into(),
+            claim_hash: row.read::<&str, _>("claim_hash").into(),
+            username: row.read::<&str, _>("username").into(),
+            status: RegistrationStatus::from_db(row.read::<&str, _>("status")),
+            client_uuid: row.read::<Option<&str>, _>("client_uuid").map(Arc::from),
+            ip: row.read::<&str, _>("ip").into(),
+            created_at: row.read::<i64, _>("created_at") as u64,
+            decided_at: row.read::<Option<i64>, _>("decided_at").map(|t| t as u64),
+            decided_by: row.read::<Option<&str>, _>("decided_by").map(String::from),
+        }
+    }
+
+    pub fn write_to_db(&self, connection: &Connection) {
+        let query = "INSERT INTO registrations (request_id, claim_hash, username, status, client_uuid, ip, created_at, decided_at, decided_by) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)";
+        let mut statement = connection.prepare(query).unwrap();
+        statement
+            .bind_iter::<_, (_, Value)>([
+                (1, self.request_id.as_ref().into()),
+                (2, self.claim_hash.as_ref().into()),
+                (3, self.username.as_str().into()),
+                (4, self.status.as_str().into()),
+                (5, self.client_uuid.as_deref().into()),
+                (6, self.ip.as_str().into()),
+                (7, (self.created_at as i64).into()),
+                (8, self.decided_at.map(|t| t as i64).into()),
+                (9, self.decided_by.as_deref().into()),
+            ])
+            .unwrap();
+        let _ = statement.next();
+    }
+
+    pub fn write_status_to_db(&self, connection: &Connection) {
+        let query = "UPDATE registrations SET status = ?, client_uuid = ?, decided_at = ?, decided_by = ? WHERE request_id = ?";
+        let mut statement = connection.prepare(query).unwrap();
+        statement
+            .bind_iter::<_, (_, Value)>([
+                (1, self.status.as_str().into()),
+                (2, self.client_uuid.as_deref().into()),
+                (3, self.decided_at.map(|t| t as i64).into()),
+                (4, self.decided_by.as_deref().into()),
+                (5, self.request_id.as_ref().into()),
+            ])
+            .unwrap();
+        let _ = statement.next();
+    }
+
+    pub fn get(connection: &Connection, request_id: &str) -> Option<Self> {
+        let query = "SELECT * FROM registrations WHERE request_id = ?";
+        connection
+            .prepare(query)
+            .unwrap()
+            .into_iter()
+            .bind((1, request_id))
+            .unwrap()
+            .map(|row| Self::from_db_row(row.unwrap()))
+            .next()
+    }
+
+    // Oldest first, every status without a filter
+    pub fn all(connection: &Connection, status: Option<RegistrationStatus>) -> Vec<Self> {
+        let query = "SELECT * FROM registrations WHERE ?1 IS NULL OR status = ?1 ORDER BY created_at, rowid";
+        connection
+            .prepare(query)
+            .unwrap()
+            .into_iter()
+            .bind((1, status.map(|s| s.as_str())))
+            .unwrap()
+            .map(|row| Self::from_db_row(row.unwrap()))
+            .collect()
+    }
+
+    // Pending requests in total and from one address
+    pub fn pending_count(connection: &Connection, ip: IpAddr) -> (usize, usize) {
+        let query = "SELECT COUNT(*) AS total, COUNT(CASE WHEN ip = ? THEN 1 END) AS from_ip FROM registrations WHERE status = 'pending'";
+        connection
+            .prepare(query)
+            .unwrap()
+            .into_iter()
+            .bind((1, ip.to_string().as_str()))
+            .unwrap()
+            .map(|row| {
+                let row = row.unwrap();
+                (
+                    row.read::<i64, _>("total") as usize,
+                    row.read::<i64, _>("from_ip") as usize,
+                )
+            })
+            .next()
+            .unwrap_or_default()
+    }
+
+    pub fn is_pending(connection: &Connection, username: &str) -> bool {
+        let query =
+            "SELECT 1 FROM registrations WHERE status = 'pending' AND username = ? COLLATE NOCASE";
+        connection
+            .prepare(query)
+            .unwrap()
+            .into_iter()
+            .bind((1, username))
+            .unwrap()
+            .next()
+            .is_some()
+    }
+
+    pub fn get_id(&self) -> Arc<str> {
+        self.request_id.clone()
+    }
+
+    pub fn verify_claim(&self, claim_token: &str, key: &TokenKey) -> bool {
+        key.verify(claim_token, &self.claim_hash)
+    }
+}
@@ -1,25 +1,348 @@
 // File Contains Structs for Server Config and Manipulation
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     fs::File,
     io::{Read, Write},
     net::{IpAddr, SocketAddr},
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
 };
 
 use futures_channel::mpsc::UnboundedSender;
 use serde::{Deserialize, Serialize};
-use sqlite::Connection;
-use tokio_tungstenite::tungstenite::Message;
+use sqlite::{Connection, Value};
+use tracing::{error, info, warn};
 
-use crate::client::Client;
+use crate::{
+    account::{self, AccountExport, DeletedMessages},
+    attachment::{Attachment, AttachmentError, ATTACHMENTS_PER_MESSAGE},
+    audit::{self, AuditEntry, AuditFilter, CLI_ACTOR},
+    blob::Blob,
+    client::{Client, PastUsername, Presence, UsernameError},
+    guest::{self, Guest},
+    invite::Invite,
+    lockout::{AuthError, Lockout, LockoutConfig},
+    logging::{self, LogConfig},
+    media::Upload,
+    message::Message,
+    metrics::{self, ServerStats, METRICS},
+    reaction::Reaction,
+    registration::{
+        DecideError, RegisterError, Registration, RegistrationMode, RegistrationStatus,
+        MAX_PENDING, MAX_PENDING_PER_ADDRESS,
+    },
+    token::{Scope, Scopes, Token, TokenKey},
+};
+
+pub type Tx = UnboundedSender<Arc<str>>;
+
+// Columns added to existing tables after they were first created
+const COLUMNS: [(&str, &str, &str); 14] = [
+    ("clients", "presence", "TEXT NOT NULL DEFAULT 'online'"),
+    ("clients", "status_text", "TEXT"),
+    ("clients", "last_seen", "INTEGER NOT NULL DEFAULT 0"),
+    ("messages", "parent_uuid", "TEXT"),
+    ("messages", "in_thread", "INTEGER NOT NULL DEFAULT 0"),
+    ("messages", "reply_count", "INTEGER NOT NULL DEFAULT 0"),
+    ("attachments", "blob_hash", "TEXT"),
+    ("attachments", "width", "INTEGER"),
+    ("attachments", "height", "INTEGER"),
+    ("attachments", "thumbnail_hash", "TEXT"),
+    ("clients", "avatar_hash", "TEXT"),
+    ("clients", "token_id", "TEXT"),
+    ("clients", "token_hash", "TEXT"),
+    ("messages", "is_guest_message", "INTEGER NOT NULL DEFAULT 0"),
+];
+
+// Indexes on columns that may only exist after migration
+const INDEXES: [&str; 8] = [
+    "DROP INDEX IF EXISTS clients_token_id;",
+    "CREATE UNIQUE INDEX IF NOT EXISTS clients_token_id_unique ON clients (token_id);",
+    "CREATE UNIQUE INDEX IF NOT EXISTS clients_uuid_unique ON clients (uuid);",
+    "CREATE INDEX IF NOT EXISTS tokens_client_uuid ON tokens (client_uuid);",
+    "CREATE UNIQUE INDEX IF NOT EXISTS clients_username_unique ON clients (username COLLATE NOCASE);",
+    "CREATE INDEX IF NOT EXISTS username_history_username ON username_history (username);",
+    "CREATE INDEX IF NOT EXISTS audit_log_actor ON audit_log (actor);",
+    "CREATE INDEX IF NOT EXISTS audit_log_target ON audit_log (target);",
+];
+
+// Tables added after the initial schema
+const TABLES: [&str; 11] = [
+    "CREATE TABLE IF NOT EXISTS messages (message_uuid TEXT PRIMARY KEY, author_uuid TEXT NOT NULL, data TEXT NOT NULL, edited INTEGER NOT NULL, unix_time INTEGER NOT NULL, is_server_message INTEGER NOT NULL);",
+    "CREATE TABLE IF NOT EXISTS reactions (message_uuid TEXT NOT NULL, client_uuid TEXT NOT NULL, emoji TEXT NOT NULL, unix_time INTEGER NOT NULL, PRIMARY KEY (message_uuid, client_uuid, emoji));",
+    "CREATE TABLE IF NOT EXISTS attachments (attachment_id TEXT PRIMARY KEY, owner_uuid TEXT NOT NULL, file_name TEXT NOT NULL, content_type TEXT NOT NULL, size INTEGER NOT NULL, unix_time INTEGER NOT NULL);",
+    "CREATE TABLE IF NOT EXISTS message_attachments (message_uuid TEXT NOT NULL, attachment_id TEXT NOT NULL, PRIMARY KEY (message_uuid, attachment_id));",
+    "CREATE TABLE IF NOT EXISTS blobs (hash TEXT PRIMARY KEY, size INTEGER NOT NULL, ref_count INTEGER NOT NULL, unix_time INTEGER NOT NULL);",
+    "CREATE TABLE IF NOT EXISTS invites (invite_id TEXT PRIMARY KEY, code_hash TEXT NOT NULL, created_by TEXT NOT NULL, max_uses INTEGER NOT NULL, uses INTEGER NOT NULL, expires_at INTEGER, created_at INTEGER NOT NULL);",
+    "CREATE TABLE IF NOT EXISTS registrations (request_id TEXT PRIMARY KEY, claim_hash TEXT NOT NULL, username TEXT NOT NULL, status TEXT NOT NULL, client_uuid TEXT, ip TEXT NOT NULL, created_at INTEGER NOT NULL, decided_at INTEGER, decided_by TEXT);",
+    "CREATE TABLE IF NOT EXISTS username_history (client_uuid TEXT NOT NULL, username TEXT NOT NULL COLLATE NOCASE, changed_at INTEGER NOT NULL, PRIMARY KEY (client_uuid, username));",
+    "CREATE TABLE IF NOT EXISTS guests (guest_id TEXT PRIMARY KEY, uuid TEXT NOT NULL UNIQUE, token_hash TEXT NOT NULL, username TEXT NOT NULL, created_by TEXT NOT NULL, expires_at INTEGER NOT NULL, created_at INTEGER NOT NULL);",
+    "CREATE TABLE IF NOT EXISTS tokens (token_id TEXT PRIMARY KEY, client_uuid TEXT NOT NULL, name TEXT NOT NULL, token_hash TEXT NOT NULL, scopes TEXT NOT NULL, expires_at INTEGER, created_at INTEGER NOT NULL);",
+    "CREATE TABLE IF NOT EXISTS audit_log (entry_id INTEGER PRIMARY KEY AUTOINCREMENT, unix_time INTEGER NOT NULL, event TEXT NOT NULL, actor TEXT, target TEXT, ip TEXT, detail TEXT NOT NULL);",
+];
+
+// Keep the audit log append only, entries may only be pruned once they are
+// older than audit::MIN_RETENTION_DAYS
+const TRIGGERS: [&str; 3] = [
+    "CREATE TRIGGER IF NOT EXISTS audit_log_no_update BEFORE UPDATE ON audit_log BEGIN SELECT RAISE(ABORT, 'audit log is append only'); END;",
+    "DROP TRIGGER IF EXISTS audit_log_no_delete;",
+    "CREATE TRIGGER IF NOT EXISTS audit_log_keep_recent BEFORE DELETE ON audit_log WHEN OLD.unix_time > CAST(strftime('%s', 'now') AS INTEGER) - 30 * 24 * 60 * 60 BEGIN SELECT RAISE(ABORT, 'audit log is append only'); END;",
+];
+
+// Seconds between garbage collections of unused blobs
+const BLOB_GC_INTERVAL: u64 = 60 * 60;
+// Seconds between prunes of expired audit entries
+const AUDIT_PRUNE_INTERVAL: u64 = 24 * 60 * 60;
+
+pub const HISTORY_LIMIT: usize = 100;
+pub const TOKENS_PER_CLIENT: usize = 32;
+
+fn default_attachment_path() -> PathBuf {
+    PathBuf::from("attachments")
+}
+
+fn default_token_key_path() -> PathBuf {
+    PathBuf::from("token.key")
+}
+
+fn default_audit_log_path() -> PathBuf {
+    PathBuf::from("audit.log")
+}
+
+fn default_max_upload_size() -> u64 {
+    8 * 1024 * 1024
+}
+
+fn default_attachment_quota() -> u64 {
+    256 * 1024 * 1024
+}
+
+fn default_drain_secs() -> u64 {
+    10
+}
+
+// Ways a websocket client may present its token, each can be turned off
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WsAuthMethods {
+    // Sec-WebSocket-Protocol: Authorization, <token>
+    pub protocol: bool,
+    // Authorization: Bearer <token>
+    pub bearer: bool,
+    // ?token=<token>, off by default as urls end up in proxy logs
+    pub query: bool,
+    // {"token": "<token>"} as the first message, for browsers that can not set headers
+    pub first_message: bool,
+}
+
+impl Default for WsAuthMethods {
+    fn default() -> Self {
+        Self {
+            protocol: true,
+            bearer: true,
+            query: false,
+            first_message: true,
+        }
+    }
+}
+
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time Travel?")
+        .as_secs()
+}
+
+fn migrate(db: &Connection) {
+    for query in TABLES {
+        db.execute(query).expect("Failed to Create Table");
+    }
+    for (table, column, definition) in COLUMNS {
+        let exists = db
+            .prepare(format!("PRAGMA table_info({table})"))
+            .unwrap()
+            .into_iter()
+            .any(|row| row.unwrap().read::<&str, _>("name") == column);
+        if !exists {
+            db.execute(format!(
+                "ALTER TABLE {table} ADD COLUMN {column} {definition}"
+            ))
+            .expect("Failed to Migrate Table");
+        }
+    }
+    migrate_usernames(db);
+    for query in INDEXES {
+        db.execute(query)
+            .expect("Failed to Create Index, are there duplicate clients?");
+    }
+    for query in TRIGGERS {
+        db.execute(query).expect("Failed to Create Trigger");
+    }
+}
 
-pub type Tx = UnboundedSender<Message>;
+// Usernames used to be unchecked, later clients sharing a name (ignoring case)
+// get a numbered suffix and keep the old name in their history
+fn migrate_usernames(db: &Connection) {
+    let query = "SELECT uuid, username FROM clients c WHERE EXISTS (SELECT 1 FROM clients e WHERE e.username = c.username COLLATE NOCASE AND e.rowid < c.rowid) ORDER BY rowid";
+    let duplicates = db
+        .prepare(query)
+        .unwrap()
+        .into_iter()
+        .map(|row| {
+            let row = row.unwrap();
+            (
+                row.read::<&str, _>("uuid").to_string(),
+                row.read::<&str, _>("username").to_string(),
+            )
+        })
+        .collect::<Vec<_>>();
+    for (uuid, username) in duplicates {
+        let mut client = Client::from_db_row(
+            db.prepare("SELECT * FROM clients WHERE uuid = ?")
+                .unwrap()
+                .into_iter()
+                .bind((1, uuid.as_str()))
+                .unwrap()
+                .next()
+                .unwrap()
+                .unwrap(),
+        );
+        let mut n = 2;
+        // Only has to be free, the old name may not follow the naming rules either
+        let renamed = loop {
+            let candidate = format!("{username}-{n}");
+            if !Client::is_username_taken(db, &candidate, None) {
+                break candidate;
+            }
+            n += 1;
+        };
+        client.write_username_to_db(db, &renamed);
+    }
+}
+
+// Replaces plain tokens with their id and keyed hash, then moves the single
+// token every client used to have into the tokens table
+fn migrate_tokens(db: &Connection, key: &TokenKey) {
+    let query = "SELECT uuid, token FROM clients WHERE token IS NOT NULL AND token_hash IS NULL";
+    let plain = db
+        .prepare(query)
+        .unwrap()
+        .into_iter()
+        .map(|row| {
+            let row = row.unwrap();
+            (
+                row.read::<&str, _>("uuid").to_string(),
+                row.read::<&str, _>("token").to_string(),
+            )
+        })
+        .collect::<Vec<_>>();
+    for (uuid, token) in plain {
+        let query = "UPDATE clients SET token_id = ?, token_hash = ?, token = NULL WHERE uuid = ?";
+        let mut statement = db.prepare(query).unwrap();
+        statement
+            .bind_iter::<_, (_, Value)>([
+                (1, key.token_id(&token).into()),
+                (2, key.hash(&token).into()),
+                (3, uuid.into()),
+            ])
+            .unwrap();
+        let _ = statement.next();
+    }
+    let query = "INSERT OR IGNORE INTO tokens (token_id, client_uuid, name, token_hash, scopes, expires_at, created_at) SELECT token_id, uuid, 'default', token_hash, ?, NULL, ? FROM clients WHERE token_hash IS NOT NULL";
+    let mut statement = db.prepare(query).unwrap();
+    statement
+        .bind_iter::<_, (_, Value)>([
+            (1, Scopes::CLIENT.to_db().into()),
+            (2, (unix_time() as i64).into()),
+        ])
+        .unwrap();
+    let _ = statement.next();
+    db.execute(
+        "UPDATE clients SET token_id = NULL, token_hash = NULL WHERE token_hash IS NOT NULL",
+    )
+    .expect("Failed to Migrate Tokens");
+}
+
+// Invites used to be stored under their plain code, those now go by the id and
+// keyed hash a code without a '.' gets, so they keep working
+fn migrate_invites(db: &Connection, key: &TokenKey) {
+    let plain = db
+        .prepare("PRAGMA table_info(invites)")
+        .unwrap()
+        .into_iter()
+        .any(|row| row.unwrap().read::<&str, _>("name") == "code");
+    if !plain {
+        return;
+    }
+    db.execute("ALTER TABLE invites RENAME COLUMN code TO invite_id; ALTER TABLE invites ADD COLUMN code_hash TEXT;")
+        .expect("Failed to Migrate Invites");
+    let codes = db
+        .prepare("SELECT invite_id FROM invites")
+        .unwrap()
+        .into_iter()
+        .map(|row| row.unwrap().read::<&str, _>("invite_id").to_string())
+        .collect::<Vec<_>>();
+    for code in codes {
+        let query = "UPDATE invites SET invite_id = ?, code_hash = ? WHERE invite_id = ?";
+        let mut statement = db.prepare(query).unwrap();
+        statement
+            .bind_iter::<_, (_, Value)>([
+                (1, key.token_id(&code).into()),
+                (2, key.hash(&code).into()),
+                (3, code.into()),
+            ])
+            .unwrap();
+        let _ = statement.next();
+    }
+}
+
+// Moves attachments stored under their id into the blob store
+fn migrate_attachments(db: &Connection, attachment_path: &Path) {
+    let query = "SELECT attachment_id FROM attachments WHERE blob_hash IS NULL";
+    let legacy = db
+        .prepare(query)
+        .unwrap()
+        .into_iter()
+        .map(|row| row.unwrap().read::<&str, _>("attachment_id").to_string())
+        .collect::<Vec<_>>();
+    for attachment_id in legacy {
+        let old_path = attachment_path.join(&attachment_id);
+        let hash = match std::fs::read(&old_path)
+            .and_then(|data| Blob::store(db, attachment_path, &data))
+        {
+            Ok(hash) => hash,
+            Err(e) => {
+                warn!(%attachment_id, error = %e, "failed to migrate attachment");
+                continue;
+            }
+        };
+        let query = "UPDATE attachments SET blob_hash = ? WHERE attachment_id = ?";
+        let mut statement = db.prepare(query).unwrap();
+        statement
+            .bind_iter::<_, (_, Value)>([
+                (1, hash.as_str().into()),
+                (2, attachment_id.as_str().into()),
+            ])
+            .unwrap();
+        let _ = statement.next();
+        let query = "UPDATE blobs SET ref_count = ref_count + (SELECT COUNT(*) FROM message_attachments WHERE attachment_id = ?) WHERE hash = ?";
+        let mut statement = db.prepare(query).unwrap();
+        statement
+            .bind_iter::<_, (_, Value)>([
+                (1, attachment_id.as_str().into()),
+                (2, hash.as_str().into()),
+            ])
+            .unwrap();
+        let _ = statement.next();
+        let _ = std::fs::remove_file(old_path);
+    }
+}
 
-#[derive(Serialize)]
-struct ClientExport {
+// What a client needs to connect, written to a file or sent to a registering user
+#[derive(Debug, Clone, Serialize)]
+pub struct ClientExport {
     server_ip: IpAddr,
     websocket_server_port: u16,
     http_server_port: u16,
@@ -27,27 +350,22 @@ struct ClientExport {
     client_token: Arc<str>,
 }
 impl ClientExport {
-    pub fn new(server: &Server, client: &Client) -> Self {
+    pub fn new(server: &Server, token: Arc<str>) -> Self {
         let addr = server.get_addr_websocket();
-        let e = Self {
+        Self {
             server_ip: addr.ip(),
             websocket_server_port: addr.port(),
             http_server_port: server.get_http_port(),
             server_name: server.server_name.clone(),
-            client_token: client.get_token(),
-        };
-        e.export(
-            format!("{:}-{:}", server.server_name.clone(), client.username).as_str(),
-            server.export_path.clone(),
-        );
-        e
+            client_token: token,
+        }
     }
 
     pub fn export(&self, file_name: &str, filepath: Option<PathBuf>) {
         let path = filepath.unwrap_or(PathBuf::from("."));
         let _ = std::fs::create_dir_all(&path);
         let mut writter = File::create(path.join(format!("{}.conf", file_name)))
-            .map_err(|e| eprintln!("Error: Failed to open file to write client data: {e}"))
+            .map_err(|e| error!(error = %e, "failed to open file to write client data"))
             .unwrap();
         let _ = writter
             .write_all(
@@ -55,7 +373,7 @@ impl ClientExport {
                     .expect("Failed to Convert Client Data to Config")
                     .as_bytes(),
             )
-            .map_err(|e| eprintln!("Failed to Write Config: {e}"));
+            .map_err(|e| error!(error = %e, "failed to write client config"));
     }
 }
 
@@ -67,10 +385,56 @@ pub struct Server {
     http_server_port: u16,
     pub export_path: Option<PathBuf>,
     pub db_path: PathBuf,
+    #[serde(default = "default_attachment_path")]
+    pub attachment_path: PathBuf,
+    // Key tokens are hashed with, kept outside the database
+    #[serde(default = "default_token_key_path")]
+    pub token_key_path: PathBuf,
+    // Log file of earlier versions, imported into the database once
+    #[serde(default = "default_audit_log_path")]
+    pub audit_log_path: PathBuf,
+    // Days audit entries are kept, forever unless set. Never fewer than
+    // audit::MIN_RETENTION_DAYS.
+    #[serde(default)]
+    pub audit_retention_days: Option<u64>,
+    #[serde(default = "default_max_upload_size")]
+    pub max_upload_size: u64,
+    #[serde(default = "default_attachment_quota")]
+    pub attachment_quota: u64,
+    #[serde(default)]
+    pub websocket_auth: WsAuthMethods,
+    // Whether anyone may sign up without an invite, pending an admin's approval
+    #[serde(default)]
+    pub registration: RegistrationMode,
+    // What happens to the messages of a deleted account
+    #[serde(default)]
+    pub deleted_messages: DeletedMessages,
+    // Thresholds for throttling addresses that fail to authenticate
+    #[serde(default)]
+    pub lockout: LockoutConfig,
+    #[serde(default)]
+    pub logging: LogConfig,
+    // How long /readyz reports not ready before the server stops
+    #[serde(default = "default_drain_secs")]
+    pub drain_secs: u64,
+    // Message of the day, shown by clients on GET /info
+    #[serde(default)]
+    pub motd: Option<Arc<str>>,
+    // Where Prometheus scrapes /metrics, off unless set
+    #[serde(default)]
+    metrics_addr: Option<SocketAddr>,
     #[serde(skip)]
     db_connection: Option<Connection>,
     #[serde(skip)]
+    token_key: Option<TokenKey>,
+    #[serde(skip)]
+    failed_auth: Lockout,
+    #[serde(skip)]
     connected_clients: HashMap<SocketAddr, Client>,
+    #[serde(skip)]
+    last_blob_gc: u64,
+    #[serde(skip)]
+    last_audit_prune: u64,
 }
 
 impl Server {
@@ -82,9 +446,18 @@ impl Server {
             .read_to_string(&mut buf)
             .expect("Failed to Read File");
         let mut s = serde_json::from_str::<Self>(&buf).expect("Failed to Parse Server Config");
+        // Before the migrations, so what they report is logged
+        logging::init(&s.logging);
         if let Some(export_path) = s.export_path {
             s.export_path = Some(path.join(export_path));
         }
+        s.attachment_path = path.join(&s.attachment_path);
+        std::fs::create_dir_all(&s.attachment_path).unwrap_or_else(|_| {
+            panic!(
+                "Failed to Create Attachment Directory at {}",
+                s.attachment_path.display()
+            )
+        });
         let new_db = !s.db_path.exists();
         let db = sqlite::open(&s.db_path).unwrap_or_else(|_| {
             panic!("Failed to Open Connection to db at {}", s.db_path.display())
@@ -93,7 +466,19 @@ impl Server {
             let query = "CREATE TABLE clients (uuid TEXT, token TEXT, username TEXT, display_name TEXT, about_me TEXT);";
             db.execute(query).expect("Failed to Create Table");
         }
+        metrics::time_queries(&db);
+        let token_key = TokenKey::load_or_create(&path.join(&s.token_key_path));
+        migrate(&db);
+        migrate_attachments(&db, &s.attachment_path);
+        migrate_tokens(&db, &token_key);
+        migrate_invites(&db, &token_key);
+        audit::import_file(&db, &path.join(&s.audit_log_path));
+        Guest::remove_expired(&db);
         s.db_connection = Some(db);
+        s.token_key = Some(token_key);
+        s.failed_auth = Lockout::new(s.lockout);
+        s.collect_blobs();
+        s.prune_audit_log();
 
         s
     }
@@ -114,55 +499,895 @@ impl Server {
         SocketAddr::new(self.server_ip, self.http_server_port)
     }
 
-    pub fn is_client_valid(&mut self, token: &str) -> Option<Client> {
-        let query = "SELECT * FROM clients WHERE token = ?";
+    // The client a token belongs to, with the scopes of that token.
+    // Unknown, expired and scopeless tokens count as a failure of the address trying them.
+    pub fn is_client_valid(&mut self, token: &str, ip: IpAddr) -> Result<Client, AuthError> {
+        let now = unix_time();
+        if let Some(retry_after) = self.failed_auth.retry_after(ip, now) {
+            METRICS.auth_failed(AuthError::Throttled(retry_after));
+            return Err(AuthError::Throttled(retry_after));
+        }
+        let key = self.token_key.as_ref().unwrap();
+        let db = self.db_connection.as_ref().unwrap();
+        let token_id = key.token_id(token);
+        let stored = Token::get(db, &token_id);
+        let client = match stored.as_ref() {
+            Some(t) => Some(t)
+                .filter(|t| t.verify(token, key) && !t.is_expired() && !t.scopes.is_empty())
+                .and_then(|t| {
+                    let mut client = self.get_client(&t.get_client_uuid())?;
+                    client.token_id = Some(t.get_id());
+                    client.scopes = t.scopes;
+                    client.expires_at = t.expires_at;
+                    Some(client)
+                }),
+            None => Guest::get(db, &token_id)
+                .filter(|g| g.verify(token, key) && !g.is_expired())
+                .map(|g| g.to_client()),
+        };
+        client.ok_or_else(|| {
+            self.failed_auth
+                .record_failure(ip, stored.map(|t| t.get_id()).as_deref(), now, db);
+            METRICS.auth_failed(AuthError::Invalid);
+            AuthError::Invalid
+        })
+    }
+
+    pub fn get_client(&self, uuid: &str) -> Option<Client> {
+        let query = "SELECT * FROM clients WHERE uuid = ?";
         self.db_connection
-            .as_mut()
+            .as_ref()
             .unwrap()
             .prepare(query)
             .unwrap()
             .into_iter()
-            .bind((1, token))
+            .bind((1, uuid))
             .unwrap()
             .map(|row| Client::from_db_row(row.unwrap()))
             .next()
     }
 
-    pub fn client_connected(&mut self, addr: SocketAddr, client: Client) {
+    pub fn client_connected(&mut self, addr: SocketAddr, mut client: Client) {
+        if client.presence == Presence::Idle {
+            client.presence = Presence::Online;
+        }
+        if client.presence != Presence::Invisible {
+            client.last_seen = unix_time();
+            client.write_presence_to_db(self.db_connection.as_ref().unwrap());
+        }
         self.connected_clients.insert(addr, client);
+        if unix_time() >= self.last_audit_prune + AUDIT_PRUNE_INTERVAL {
+            self.prune_audit_log();
+        }
     }
     pub fn set_connected_client_tx(&mut self, addr: &SocketAddr, tx: Tx) {
         self.connected_clients.get_mut(addr).unwrap().tx = Some(tx);
     }
 
     pub fn client_disconnected(&mut self, addr: &SocketAddr) {
-        self.connected_clients.remove(addr);
+        if let Some(mut client) = self.connected_clients.remove(addr) {
+            if client.presence != Presence::Invisible {
+                client.last_seen = unix_time();
+                client.write_presence_to_db(self.db_connection.as_ref().unwrap());
+            }
+        }
+    }
+
+    // Applies to every session of the client connected at addr
+    pub fn set_presence(
+        &mut self,
+        addr: &SocketAddr,
+        presence: Presence,
+        status_text: Option<String>,
+    ) -> Option<Client> {
+        let uuid = self.connected_clients.get(addr)?.get_uuid();
+        let now = unix_time();
+        for client in self
+            .connected_clients
+            .values_mut()
+            .filter(|c| c.get_uuid() == uuid)
+        {
+            client.presence = presence;
+            client.status_text = status_text.clone();
+            if presence != Presence::Invisible {
+                client.last_seen = now;
+            }
+        }
+        let client = self.connected_clients.get(addr).cloned()?;
+        client.write_presence_to_db(self.db_connection.as_ref().unwrap());
+        Some(client)
     }
 
     pub fn get_connected_clients(&self) -> HashMap<SocketAddr, Client> {
         self.connected_clients.clone()
     }
 
-    pub fn is_client_connected_by_token(&self, token: &str) -> bool {
-        self.connected_clients
+    pub fn get_server_stats(&self) -> ServerStats {
+        let queued = self
+            .connected_clients
+            .values()
+            .filter_map(|client| client.tx.as_ref())
+            .map(|tx| tx.len());
+        ServerStats {
+            connected_clients: self
+                .connected_clients
+                .values()
+                .map(|client| client.get_uuid())
+                .collect::<HashSet<_>>()
+                .len(),
+            sessions: self.connected_clients.len(),
+            queued_payloads: queued.clone().sum(),
+            max_queued_payloads: queued.max().unwrap_or(0),
+        }
+    }
+
+    pub fn is_db_reachable(&self) -> bool {
+        self.db_connection
+            .as_ref()
+            .is_some_and(|db| db.execute("SELECT 1").is_ok())
+    }
+
+    pub fn get_metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_addr
+    }
+
+    pub fn new_client(&mut self, username: &str, admin: bool) -> Result<(), UsernameError> {
+        let scopes = if admin {
+            Scopes::CLIENT.with(Scope::Admin)
+        } else {
+            Scopes::CLIENT
+        };
+        let client = self.create_client(username, Some(CLI_ACTOR), None)?;
+        self.export_default_token(client.get_uuid(), scopes, Some(CLI_ACTOR), None)
+            .export(
+                format!("{:}-{:}", self.server_name.clone(), client.username).as_str(),
+                self.export_path.clone(),
+            );
+        Ok(())
+    }
+
+    // Guest made on the server host, for the default lifetime
+    pub fn new_guest(&mut self, username: &str) -> Result<(), UsernameError> {
+        let (guest, export) = self.create_guest(
+            CLI_ACTOR.into(),
+            username,
+            unix_time().saturating_add(guest::DEFAULT_LIFETIME),
+            None,
+        )?;
+        export.export(
+            format!("{:}-{:}", self.server_name.clone(), guest.username).as_str(),
+            self.export_path.clone(),
+        );
+        Ok(())
+    }
+
+    fn create_client(
+        &mut self,
+        username: &str,
+        actor: Option<&str>,
+        ip: Option<IpAddr>,
+    ) -> Result<Client, UsernameError> {
+        let db = self.db_connection.as_ref().unwrap();
+        let client = Client::new(username, db)?;
+        AuditEntry::record(
+            db,
+            "client_created",
+            actor,
+            Some(&client.get_uuid()),
+            ip,
+            &client.username,
+        );
+        Ok(client)
+    }
+
+    // Applies to every session of the client, the old name stays in its history
+    pub fn rename_client(
+        &mut self,
+        uuid: &str,
+        username: &str,
+        ip: IpAddr,
+    ) -> Result<Client, UsernameError> {
+        let mut client = self.get_client(uuid).ok_or(UsernameError::Invalid)?;
+        let old = client.username.clone();
+        client.rename(self.db_connection.as_ref().unwrap(), username)?;
+        if client.username == old {
+            return Ok(client);
+        }
+        for connected in self
+            .connected_clients
+            .values_mut()
+            .filter(|c| c.get_uuid().as_ref() == uuid)
+        {
+            connected.username = client.username.clone();
+        }
+        AuditEntry::record(
+            self.db_connection.as_ref().unwrap(),
+            "client_renamed",
+            Some(uuid),
+            Some(uuid),
+            Some(ip),
+            &format!("from {old} to {}", client.username),
+        );
+        Ok(client)
+    }
+
+    pub fn export_account(&self, uuid: &str) -> Option<AccountExport> {
+        let client = self.get_client(uuid)?;
+        Some(AccountExport::new(
+            self.db_connection.as_ref().unwrap(),
+            client,
+        ))
+    }
+
+    // Closes every session of the client, returns whether it existed
+    pub fn delete_account(&mut self, uuid: &str, ip: IpAddr) -> bool {
+        let Some(client) = self.get_client(uuid) else {
+            return false;
+        };
+        if !account::delete(
+            self.db_connection.as_ref().unwrap(),
+            &client,
+            self.deleted_messages,
+        ) {
+            return false;
+        }
+        // The sessions end without telling anyone, there is nobody left to be
+        // shown as disconnected or offline
+        self.connected_clients.retain(|_, connected| {
+            if connected.get_uuid().as_ref() != uuid {
+                return true;
+            }
+            if let Some(tx) = connected.tx.as_ref() {
+                tx.close_channel();
+            }
+            false
+        });
+        AuditEntry::record(
+            self.db_connection.as_ref().unwrap(),
+            "account_deleted",
+            Some(uuid),
+            Some(uuid),
+            Some(ip),
+            &client.username,
+        );
+        true
+    }
+
+    pub fn get_username_history(&self, uuid: &str) -> Vec<PastUsername> {
+        Client::username_history(self.db_connection.as_ref().unwrap(), uuid)
+    }
+
+    pub fn find_client_by_username(&self, username: &str) -> Option<Client> {
+        Client::find_by_username(self.db_connection.as_ref().unwrap(), username)
+    }
+
+    // Gives a new client its default token, returning the config it connects with
+    fn export_default_token(
+        &mut self,
+        client_uuid: Arc<str>,
+        scopes: Scopes,
+        actor: Option<&str>,
+        ip: Option<IpAddr>,
+    ) -> ClientExport {
+        let db = self.db_connection.as_ref().unwrap();
+        let (info, token) = Token::create(
+            db,
+            self.token_key.as_ref().unwrap(),
+            client_uuid.clone(),
+            "default",
+            scopes,
+            None,
+        );
+        AuditEntry::record(
+            db,
+            "token_created",
+            actor,
+            Some(&info.get_id()),
+            ip,
+            &format!("default for {client_uuid} with {}", scopes.to_db()),
+        );
+        ClientExport::new(self, token)
+    }
+
+    // Expired guests are purged whenever a new one is made
+    pub fn create_guest(
+        &mut self,
+        created_by: Arc<str>,
+        username: &str,
+        expires_at: u64,
+        ip: Option<IpAddr>,
+    ) -> Result<(Guest, ClientExport), UsernameError> {
+        let db = self.db_connection.as_ref().unwrap();
+        Guest::remove_expired(db);
+        let (guest, token) = Guest::new(
+            db,
+            self.token_key.as_ref().unwrap(),
+            username,
+            created_by.clone(),
+            expires_at,
+        )?;
+        AuditEntry::record(
+            db,
+            "guest_created",
+            Some(&created_by),
+            Some(&guest.get_id()),
+            ip,
+            &format!(
+                "{} ({}) until {expires_at}",
+                guest.username,
+                guest.get_uuid()
+            ),
+        );
+        Ok((guest, ClientExport::new(self, token)))
+    }
+
+    pub fn get_guests(&self) -> Vec<Guest> {
+        Guest::all(self.db_connection.as_ref().unwrap())
+    }
+
+    // Closes every session of the guest, returns whether it existed
+    pub fn revoke_guest(&mut self, guest_id: &str, revoked_by: &str, ip: IpAddr) -> bool {
+        let db = self.db_connection.as_ref().unwrap();
+        let Some(guest) = Guest::get(db, guest_id) else {
+            return false;
+        };
+        Guest::revoke(db, guest_id);
+        for connected in self
+            .connected_clients
             .values()
-            .any(|c| c.get_token() == token.into())
+            .filter(|c| c.get_uuid() == guest.get_uuid())
+        {
+            if let Some(tx) = connected.tx.as_ref() {
+                tx.close_channel();
+            }
+        }
+        AuditEntry::record(
+            db,
+            "guest_revoked",
+            Some(revoked_by),
+            Some(guest_id),
+            Some(ip),
+            &format!("{} ({})", guest.username, guest.get_uuid()),
+        );
+        true
+    }
+
+    pub fn create_invite(
+        &mut self,
+        created_by: Arc<str>,
+        max_uses: u32,
+        expires_at: Option<u64>,
+        ip: IpAddr,
+    ) -> (Invite, Arc<str>) {
+        let db = self.db_connection.as_ref().unwrap();
+        let (invite, code) = Invite::new(
+            db,
+            self.token_key.as_ref().unwrap(),
+            created_by.clone(),
+            max_uses,
+            expires_at,
+        );
+        AuditEntry::record(
+            db,
+            "invite_created",
+            Some(&created_by),
+            Some(&invite.get_id()),
+            Some(ip),
+            &format!("{max_uses} uses"),
+        );
+        (invite, code)
+    }
+
+    pub fn get_invites(&self) -> Vec<Invite> {
+        Invite::all(self.db_connection.as_ref().unwrap())
+    }
+
+    pub fn revoke_invite(&mut self, invite_id: &str, revoked_by: &str, ip: IpAddr) -> bool {
+        let db = self.db_connection.as_ref().unwrap();
+        let revoked = Invite::revoke(db, invite_id);
+        if revoked {
+            AuditEntry::record(
+                db,
+                "invite_revoked",
+                Some(revoked_by),
+                Some(invite_id),
+                Some(ip),
+                "",
+            );
+        }
+        revoked
+    }
+
+    // Unknown invite codes count as failed authentication of the address
+    pub fn register(
+        &mut self,
+        ip: IpAddr,
+        code: &str,
+        username: &str,
+    ) -> Result<ClientExport, RegisterError> {
+        let now = unix_time();
+        if let Some(retry_after) = self.failed_auth.retry_after(ip, now) {
+            return Err(RegisterError::Throttled(retry_after));
+        }
+        if !Client::is_valid_username(username) {
+            return Err(RegisterError::InvalidUsername);
+        }
+        if self.is_username_unavailable(username) {
+            return Err(RegisterError::UsernameTaken);
+        }
+        let db = self.db_connection.as_ref().unwrap();
+        let Some(invite_id) = Invite::redeem(db, self.token_key.as_ref().unwrap(), code) else {
+            self.failed_auth.record_failure(ip, None, now, db);
+            return Err(RegisterError::InvalidInvite);
+        };
+        let client = self
+            .create_client(username, None, Some(ip))
+            .map_err(|_| RegisterError::UsernameTaken)?;
+        AuditEntry::record(
+            self.db_connection.as_ref().unwrap(),
+            "client_registered",
+            None,
+            Some(&client.get_uuid()),
+            Some(ip),
+            &format!("with invite {invite_id}"),
+        );
+        Ok(self.export_default_token(client.get_uuid(), Scopes::CLIENT, None, Some(ip)))
+    }
+
+    // Queues a sign up without an invite, returns it with the token to claim it by
+    pub fn request_registration(
+        &mut self,
+        ip: IpAddr,
+        username: &str,
+    ) -> Result<(Registration, Arc<str>), RegisterError> {
+        if self.registration != RegistrationMode::Approval {
+            return Err(RegisterError::Closed);
+        }
+        if !Client::is_valid_username(username) {
+            return Err(RegisterError::InvalidUsername);
+        }
+        if self.is_username_unavailable(username) {
+            return Err(RegisterError::UsernameTaken);
+        }
+        let db = self.db_connection.as_ref().unwrap();
+        let (total, from_ip) = Registration::pending_count(db, ip);
+        if total >= MAX_PENDING || from_ip >= MAX_PENDING_PER_ADDRESS {
+            return Err(RegisterError::QueueFull);
+        }
+        let (registration, claim_token) =
+            Registration::new(db, self.token_key.as_ref().unwrap(), username, ip);
+        AuditEntry::record(
+            db,
+            "registration_requested",
+            None,
+            Some(&registration.get_id()),
+            Some(ip),
+            username,
+        );
+        Ok((registration, claim_token))
+    }
+
+    // Taken by a client or by a sign up still waiting for approval
+    fn is_username_unavailable(&self, username: &str) -> bool {
+        let db = self.db_connection.as_ref().unwrap();
+        Client::is_username_taken(db, username, None) || Registration::is_pending(db, username)
+    }
+
+    pub fn get_registrations(&self, status: Option<RegistrationStatus>) -> Vec<Registration> {
+        Registration::all(self.db_connection.as_ref().unwrap(), status)
+    }
+
+    // Approving creates the client, its token is made when the applicant claims it
+    pub fn decide_registration(
+        &mut self,
+        request_id: &str,
+        approve: bool,
+        decided_by: &str,
+        ip: Option<IpAddr>,
+    ) -> Result<Registration, DecideError> {
+        let mut registration = Registration::get(self.db_connection.as_ref().unwrap(), request_id)
+            .ok_or(DecideError::Unknown)?;
+        if registration.status != RegistrationStatus::Pending {
+            return Err(DecideError::AlreadyDecided);
+        }
+        if approve {
+            let client = self
+                .create_client(&registration.username, Some(decided_by), ip)
+                .map_err(|_| DecideError::UsernameTaken)?;
+            registration.client_uuid = Some(client.get_uuid());
+            registration.status = RegistrationStatus::Approved;
+        } else {
+            registration.status = RegistrationStatus::Rejected;
+        }
+        registration.decided_at = Some(unix_time());
+        registration.decided_by = Some(decided_by.to_string());
+        registration.write_status_to_db(self.db_connection.as_ref().unwrap());
+        AuditEntry::record(
+            self.db_connection.as_ref().unwrap(),
+            if approve {
+                "registration_approved"
+            } else {
+                "registration_rejected"
+            },
+            Some(decided_by),
+            Some(request_id),
+            ip,
+            &registration.username,
+        );
+        Ok(registration)
+    }
+
+    // Config of an approved sign up, handed out once.
+    // Unknown claim tokens count as failed authentication of the address.
+    pub fn claim_registration(
+        &mut self,
+        ip: IpAddr,
+        claim_token: &str,
+    ) -> Result<ClientExport, RegisterError> {
+        let now = unix_time();
+        if let Some(retry_after) = self.failed_auth.retry_after(ip, now) {
+            return Err(RegisterError::Throttled(retry_after));
+        }
+        let key = self.token_key.as_ref().unwrap();
+        let Some(mut registration) = Registration::get(
+            self.db_connection.as_ref().unwrap(),
+            &key.token_id(claim_token),
+        )
+        .filter(|r| r.verify_claim(claim_token, key)) else {
+            self.failed_auth
+                .record_failure(ip, None, now, self.db_connection.as_ref().unwrap());
+            return Err(RegisterError::UnknownClaim);
+        };
+        let client_uuid = match (registration.status, registration.client_uuid.clone()) {
+            (RegistrationStatus::Pending, _) => return Err(RegisterError::Pending),
+            (RegistrationStatus::Rejected, _) => return Err(RegisterError::Rejected),
+            (RegistrationStatus::Approved, Some(client_uuid)) => client_uuid,
+            _ => return Err(RegisterError::AlreadyClaimed),
+        };
+        registration.status = RegistrationStatus::Claimed;
+        registration.write_status_to_db(self.db_connection.as_ref().unwrap());
+        AuditEntry::record(
+            self.db_connection.as_ref().unwrap(),
+            "registration_claimed",
+            None,
+            Some(&registration.get_id()),
+            Some(ip),
+            &client_uuid,
+        );
+        Ok(self.export_default_token(client_uuid, Scopes::CLIENT, None, Some(ip)))
+    }
+
+    pub fn get_tokens(&self, client_uuid: &str) -> Vec<Token> {
+        Token::for_client(self.db_connection.as_ref().unwrap(), client_uuid)
+    }
+
+    // Returns the new token and its secret, None once the client has TOKENS_PER_CLIENT
+    pub fn create_token(
+        &mut self,
+        client_uuid: Arc<str>,
+        name: &str,
+        scopes: Scopes,
+        expires_at: Option<u64>,
+        ip: IpAddr,
+    ) -> Option<(Token, Arc<str>)> {
+        let db = self.db_connection.as_ref().unwrap();
+        if Token::for_client(db, &client_uuid).len() >= TOKENS_PER_CLIENT {
+            return None;
+        }
+        let (info, token) = Token::create(
+            db,
+            self.token_key.as_ref().unwrap(),
+            client_uuid.clone(),
+            name,
+            scopes,
+            expires_at,
+        );
+        AuditEntry::record(
+            db,
+            "token_created",
+            Some(&client_uuid),
+            Some(&info.get_id()),
+            Some(ip),
+            &format!("{name} with {}", scopes.to_db()),
+        );
+        Some((info, token))
+    }
+
+    pub fn revoke_token(&mut self, client_uuid: &str, token_id: &str, ip: IpAddr) -> bool {
+        let db = self.db_connection.as_ref().unwrap();
+        let revoked = Token::revoke(db, client_uuid, token_id);
+        if revoked {
+            for connected in self
+                .connected_clients
+                .values()
+                .filter(|c| c.token_id.as_deref() == Some(token_id))
+            {
+                if let Some(tx) = connected.tx.as_ref() {
+                    tx.close_channel();
+                }
+            }
+            AuditEntry::record(
+                db,
+                "token_revoked",
+                Some(client_uuid),
+                Some(token_id),
+                Some(ip),
+                "",
+            );
+        }
+        revoked
     }
 
-    pub fn new_client(&mut self, username: &str) {
-        let client = Client::new(username, self.db_connection.as_mut().unwrap());
-        ClientExport::new(self, &client);
+    pub fn get_audit_log(&self, filter: &AuditFilter) -> Vec<AuditEntry> {
+        AuditEntry::query(self.db_connection.as_ref().unwrap(), filter)
     }
 
+    // Guests that have not expired are listed after the clients
     pub fn get_all_clients(&mut self) -> Vec<Client> {
         let query = "SELECT * FROM clients";
+        let db = self.db_connection.as_ref().unwrap();
+        let mut clients = db
+            .prepare(query)
+            .unwrap()
+            .into_iter()
+            .map(|row| Client::from_db_row(row.unwrap()))
+            .collect::<Vec<_>>();
+        clients.extend(Guest::all(db).iter().map(Guest::to_client));
+        clients
+    }
+
+    // Returns the message as stored, None if it replies to an unknown message
+    // or references attachments that are unknown or were uploaded by someone else
+    pub fn store_message(
+        &mut self,
+        mut message: Message,
+        mut attachment_ids: Vec<String>,
+    ) -> Option<Message> {
+        let db = self.db_connection.as_ref().unwrap();
+        let mut seen = HashSet::new();
+        attachment_ids.retain(|id| seen.insert(id.clone()));
+        if attachment_ids.len() > ATTACHMENTS_PER_MESSAGE {
+            return None;
+        }
+        message.attachments = attachment_ids
+            .iter()
+            .map(|id| {
+                Attachment::get(db, id)
+                    .filter(|attachment| attachment.get_owner_uuid() == message.get_author_uuid())
+            })
+            .collect::<Option<Vec<_>>>()?;
+        if let Some(parent_uuid) = message.parent_uuid.clone() {
+            let parent = self.get_message(&parent_uuid)?;
+            // Replies to a message inside a thread stay in that thread
+            if parent.in_thread {
+                message.parent_uuid = parent.parent_uuid.clone();
+                message.in_thread = true;
+            }
+            if message.mention_parent {
+                message.parent_author_uuid = Some(parent.get_author_uuid());
+            }
+            let query = "UPDATE messages SET reply_count = reply_count + 1 WHERE message_uuid = ?";
+            let mut statement = db.prepare(query).unwrap();
+            statement
+                .bind((1, message.parent_uuid.as_deref().unwrap()))
+                .unwrap();
+            let _ = statement.next();
+        }
+        while self.message_exists(&message.get_uuid()) {
+            message.regenerate_uuid();
+        }
+        message.write_to_db(db);
+        for attachment in message.attachments.iter() {
+            attachment.link_to_message(db, &message.get_uuid());
+        }
+        METRICS.message_stored();
+        Some(message)
+    }
+
+    pub fn get_message(&self, message_uuid: &str) -> Option<Message> {
+        let query = "SELECT * FROM messages WHERE message_uuid = ?";
         self.db_connection
-            .as_mut()
+            .as_ref()
             .unwrap()
             .prepare(query)
             .unwrap()
             .into_iter()
-            .map(|row| Client::from_db_row(row.unwrap()))
-            .collect::<Vec<_>>()
+            .bind((1, message_uuid))
+            .unwrap()
+            .map(|row| Message::from_db_row(row.unwrap()))
+            .next()
+    }
+
+    pub fn message_exists(&self, message_uuid: &str) -> bool {
+        self.get_message(message_uuid).is_some()
+    }
+
+    // Returns the updated reaction, None if the message is unknown or nothing changed
+    pub fn react(
+        &mut self,
+        client_uuid: &str,
+        message_uuid: &str,
+        emoji: &str,
+        added: bool,
+    ) -> Option<Reaction> {
+        if !self.message_exists(message_uuid) {
+            return None;
+        }
+        let db = self.db_connection.as_ref().unwrap();
+        let changed = if added {
+            Reaction::add(db, message_uuid, client_uuid, emoji)
+        } else {
+            Reaction::remove(db, message_uuid, client_uuid, emoji)
+        };
+        if changed {
+            Some(Reaction::for_emoji(db, message_uuid, emoji))
+        } else {
+            None
+        }
+    }
+
+    // Up to limit messages before the given one, oldest first.
+    // Without a thread, messages posted inside threads are left out.
+    pub fn get_history(
+        &mut self,
+        thread: Option<String>,
+        before: Option<String>,
+        limit: usize,
+    ) -> Vec<Message> {
+        let query = "SELECT * FROM messages WHERE ((?1 IS NULL AND in_thread = 0) OR (parent_uuid = ?1 AND in_thread = 1)) AND rowid < COALESCE((SELECT rowid FROM messages WHERE message_uuid = ?2), 9223372036854775807) ORDER BY rowid DESC LIMIT ?3";
+        let db = self.db_connection.as_ref().unwrap();
+        let mut messages = db
+            .prepare(query)
+            .unwrap()
+            .into_iter()
+            .bind_iter::<_, (_, Value)>([
+                (1, thread.into()),
+                (2, before.into()),
+                (3, (limit.min(HISTORY_LIMIT) as i64).into()),
+            ])
+            .unwrap()
+            .map(|row| Message::from_db_row(row.unwrap()))
+            .collect::<Vec<_>>();
+        messages.reverse();
+        let uuids = messages.iter().map(|m| m.get_uuid()).collect::<Vec<_>>();
+        let mut reactions = Reaction::for_messages(db, &uuids);
+        let mut attachments = Attachment::for_messages(db, &uuids);
+        for message in messages.iter_mut() {
+            message.reactions = reactions.remove(&message.get_uuid()).unwrap_or_default();
+            message.attachments = attachments.remove(&message.get_uuid()).unwrap_or_default();
+        }
+        messages
+    }
+
+    pub fn store_attachment(
+        &mut self,
+        owner_uuid: Arc<str>,
+        file_name: &str,
+        upload: Upload,
+    ) -> Result<Attachment, AttachmentError> {
+        let size = upload.data.len() as u64;
+        if size > self.max_upload_size {
+            return Err(AttachmentError::TooLarge);
+        }
+        let db = self.db_connection.as_ref().unwrap();
+        if Attachment::used_storage(db, &owner_uuid) + size > self.attachment_quota {
+            return Err(AttachmentError::QuotaExceeded);
+        }
+        let store = |data: &[u8]| {
+            Blob::store(db, &self.attachment_path, data).map_err(|e| {
+                error!(error = %e, "failed to write attachment");
+                AttachmentError::Storage
+            })
+        };
+        let hash = store(&upload.data)?;
+        let thumbnail_hash = match upload.image.as_ref().and_then(|i| i.thumbnail.as_ref()) {
+            Some(thumbnail) => Some(store(thumbnail)?),
+            None => None,
+        };
+        let mut attachment = Attachment::new(
+            owner_uuid,
+            file_name,
+            &upload,
+            &hash,
+            thumbnail_hash.as_deref(),
+        );
+        while Attachment::get(db, &attachment.get_id()).is_some() {
+            attachment.regenerate_id();
+        }
+        attachment.write_to_db(db);
+        if unix_time() >= self.last_blob_gc + BLOB_GC_INTERVAL {
+            self.collect_blobs();
+        }
+        Ok(attachment)
+    }
+
+    // Attachment metadata and the path of its contents on disk.
+    // Images that already fit in a thumbnail are their own thumbnail.
+    pub fn get_attachment(
+        &mut self,
+        attachment_id: &str,
+        thumbnail: bool,
+    ) -> Option<(Attachment, PathBuf)> {
+        let attachment = Attachment::get(self.db_connection.as_ref().unwrap(), attachment_id)?;
+        let hash = match thumbnail {
+            true if !attachment.is_image() => return None,
+            true => attachment
+                .get_thumbnail_hash()
+                .unwrap_or(attachment.get_blob_hash()),
+            false => attachment.get_blob_hash(),
+        };
+        let path = Blob::path(&self.attachment_path, &hash);
+        Some((attachment, path))
+    }
+
+    // Replaces the avatar of a client, None removes it.
+    // Returns the updated client, None if the avatar could not be stored.
+    pub fn set_avatar(&mut self, uuid: &str, avatar: Option<Vec<u8>>) -> Option<Client> {
+        let mut client = self.get_client(uuid)?;
+        let db = self.db_connection.as_ref().unwrap();
+        let hash = match avatar {
+            Some(data) => Some(Arc::from(
+                Blob::store(db, &self.attachment_path, &data)
+                    .map_err(|e| error!(%uuid, error = %e, "failed to write avatar"))
+                    .ok()?,
+            )),
+            None => None,
+        };
+        if hash == client.avatar {
+            return Some(client);
+        }
+        if let Some(hash) = hash.as_deref() {
+            Blob::retain(db, hash);
+        }
+        if let Some(old) = client.avatar.as_deref() {
+            let _ = Blob::release(db, old);
+        }
+        client.avatar = hash;
+        client.write_avatar_to_db(db);
+        for connected in self
+            .connected_clients
+            .values_mut()
+            .filter(|c| c.get_uuid().as_ref() == uuid)
+        {
+            connected.avatar = client.avatar.clone();
+        }
+        Some(client)
+    }
+
+    // Avatar hash of a client and the path of the picture on disk
+    pub fn get_avatar(&self, uuid: &str) -> Option<(Arc<str>, PathBuf)> {
+        let hash = self.get_client(uuid)?.avatar?;
+        let path = Blob::path(&self.attachment_path, &hash);
+        Some((hash, path))
+    }
+
+    pub fn collect_blobs(&mut self) {
+        self.last_blob_gc = unix_time();
+        let removed =
+            Blob::collect_garbage(self.db_connection.as_ref().unwrap(), &self.attachment_path);
+        if removed > 0 {
+            info!(removed, "removed unused attachment blobs");
+        }
+    }
+
+    pub fn prune_audit_log(&mut self) {
+        self.last_audit_prune = unix_time();
+        let Some(days) = self.audit_retention_days else {
+            return;
+        };
+        let db = self.db_connection.as_ref().unwrap();
+        let before = unix_time().saturating_sub(days.max(audit::MIN_RETENTION_DAYS) * 24 * 60 * 60);
+        let removed = audit::prune(db, before);
+        if removed > 0 {
+            info!(removed, "pruned expired audit entries");
+            AuditEntry::record(
+                db,
+                "audit_pruned",
+                None,
+                None,
+                None,
+                &format!("{removed} entries before {before}"),
+            );
+        }
     }
 }
@@ -0,0 +1,394 @@
+// File Contains Generation and Keyed Hashing of Client Tokens
+//
+// Tokens have the form "[<prefix>_]<token id>.<secret>":
+//  - prefix: optional, names the kind of token, "tensor" for client tokens
+//  - token id: 12 alphanumerics, unique, used to look the token up
+//  - secret: 32 alphanumerics from the operating system's CSPRNG
+// Only the id and a keyed hash of the whole token are stored, so a copy of the
+// database alone can not be used to log in.
+// Tokens from before ids were embedded get an id derived from the token itself.
+//
+// A client can hold several named tokens, each with its own scopes and optional expiry.
+
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::Arc,
+};
+
+use hmac::{Hmac, Mac};
+use rand::{
+    distributions::{Alphanumeric, DistString},
+    rngs::OsRng,
+    RngCore,
+};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use sqlite::{Connection, Row, Value};
+use subtle::ConstantTimeEq;
+
+use crate::server::unix_time;
+
+const KEY_LEN: usize = 32;
+const TOKEN_ID_LEN: usize = 12;
+const SECRET_LEN: usize = 32;
+const LEGACY_ID_LEN: usize = 16;
+
+pub const CLIENT_PREFIX: &str = "tensor";
+pub const BOT_PREFIX: &str = "tensorbot";
+
+pub struct TokenKey {
+    key: Vec<u8>,
+}
+
+impl TokenKey {
+    // Reads the hex encoded key at path, creating a random one readable only by the owner
+    pub fn load_or_create(path: &Path) -> Self {
+        if let Ok(hex) = fs::read_to_string(path) {
+            let key = hex
+                .trim()
+                .as_bytes()
+                .chunks(2)
+                .map(|pair| {
+                    std::str::from_utf8(pair)
+                        .ok()
+                        .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                })
+                .collect::<Option<Vec<_>>>()
+                .filter(|key| !key.is_empty())
+                .unwrap_or_else(|| panic!("Invalid Token Key in {}", path.display()));
+            return Self { key };
+        }
+        let mut key = vec![0; KEY_LEN];
+        OsRng.fill_bytes(&mut key);
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(path)
+            .and_then(|mut file| {
+                file.write_all(
+                    key.iter()
+                        .map(|b| format!("{b:02x}"))
+                        .collect::<String>()
+                        .as_bytes(),
+                )
+            })
+            .unwrap_or_else(|e| panic!("Failed to Write Token Key to {}: {e}", path.display()));
+        Self { key }
+    }
+
+    fn mac(&self, domain: &str, token: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.key).unwrap();
+        mac.update(domain.as_bytes());
+        mac.update(token.as_bytes());
+        format!("{:x}", mac.finalize().into_bytes())
+    }
+
+    pub fn hash(&self, token: &str) -> String {
+        self.mac("token:", token)
+    }
+
+    pub fn token_id(&self, token: &str) -> String {
+        match token.split_once('.') {
+            Some((token_id, _)) => token_id
+                .rsplit_once('_')
+                .map_or(token_id, |(_, token_id)| token_id)
+                .to_string(),
+            None => self.mac("token-id:", token)[..LEGACY_ID_LEN].to_string(),
+        }
+    }
+
+    pub fn verify(&self, token: &str, token_hash: &str) -> bool {
+        self.hash(token)
+            .as_bytes()
+            .ct_eq(token_hash.as_bytes())
+            .into()
+    }
+}
+
+pub fn generate_token_id() -> Arc<str> {
+    Alphanumeric.sample_string(&mut OsRng, TOKEN_ID_LEN).into()
+}
+
+// New token for the given id
+pub fn generate(prefix: Option<&str>, token_id: &str) -> Arc<str> {
+    let secret = Alphanumeric.sample_string(&mut OsRng, SECRET_LEN);
+    match prefix {
+        Some(prefix) => format!("{prefix}_{token_id}.{secret}").into(),
+        None => format!("{token_id}.{secret}").into(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    // Connect, receive messages and use every GET endpoint
+    Read,
+    // Send messages, react, set presence, upload and manage own tokens
+    Send,
+    // Everything, including server administration
+    Admin,
+    // Send as an automated client, without managing tokens
+    Bot,
+}
+
+const ALL_SCOPES: [Scope; 4] = [Scope::Read, Scope::Send, Scope::Admin, Scope::Bot];
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Read => "read",
+            Self::Send => "send",
+            Self::Admin => "admin",
+            Self::Bot => "bot",
+        }
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "Vec<Scope>", into = "Vec<Scope>")]
+pub struct Scopes(u8);
+
+impl Scopes {
+    // Granted to the first token of a new client
+    pub const CLIENT: Self = Self(1 << Scope::Read as u8 | 1 << Scope::Send as u8);
+
+    pub fn with(self, scope: Scope) -> Self {
+        Self(self.0 | scope.bit())
+    }
+
+    pub fn contains(&self, scope: Scope) -> bool {
+        self.0 & scope.bit() != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn is_subset(&self, other: &Self) -> bool {
+        self.0 & !other.0 == 0
+    }
+
+    // Admins may hand out any scope, bot included, everyone else only their own
+    pub fn can_grant(&self, scopes: &Self) -> bool {
+        self.is_admin() || scopes.is_subset(self)
+    }
+
+    pub fn can_send(&self) -> bool {
+        self.contains(Scope::Send) || self.contains(Scope::Bot) || self.is_admin()
+    }
+
+    pub fn can_manage_tokens(&self) -> bool {
+        self.contains(Scope::Send) || self.is_admin()
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.contains(Scope::Admin)
+    }
+
+    // Comma separated, unknown names are ignored
+    pub fn from_db(value: &str) -> Self {
+        value
+            .split(',')
+            .filter_map(|name| ALL_SCOPES.into_iter().find(|s| s.as_str() == name.trim()))
+            .fold(Self::default(), Self::with)
+    }
+
+    pub fn to_db(self) -> String {
+        Vec::from(self)
+            .iter()
+            .map(Scope::as_str)
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+impl From<Vec<Scope>> for Scopes {
+    fn from(scopes: Vec<Scope>) -> Self {
+        scopes.into_iter().fold(Self::default(), Self::with)
+    }
+}
+
+impl From<Scopes> for Vec<Scope> {
+    fn from(scopes: Scopes) -> Self {
+        ALL_SCOPES
+            .into_iter()
+            .filter(|scope| scopes.contains(*scope))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Token {
+    token_id: Arc<str>,
+    #[serde(skip)]
+    client_uuid: Arc<str>,
+    #[serde(skip)]
+    token_hash: Arc<str>,
+    pub name: String,
+    pub scopes: Scopes,
+    pub expires_at: Option<u64>,
+    pub created_at: u64,
+}
+
+impl Token {
+    // The token itself is only ever known here, only its hash is stored
+    pub fn create(
+        connection: &Connection,
+        key: &TokenKey,
+        client_uuid: Arc<str>,
+        name: &str,
+        scopes: Scopes,
+        expires_at: Option<u64>,
+    ) -> (Self, Arc<str>) {
+        let mut token_id = generate_token_id();
+        while Self::get(connection, &token_id).is_some() {
+            token_id = generate_token_id();
+        }
+        let prefix = if scopes.contains(Scope::Bot) {
+            BOT_PREFIX
+        } else {
+            CLIENT_PREFIX
+        };
+        let token = generate(Some(prefix), &token_id);
+        let s = Self {
+            token_id,
+            client_uuid,
+            token_hash: key.hash(&token).into(),
+            name: name.to_string(),
+            scopes,
+            expires_at,
+            created_at: unix_time(),
+        };
+        s.write_to_db(connection);
+        (s, token)
+    }
+
+    pub fn from_db_row(row: Row) -> Self {
+        Self {
+            token_id: row.read::<&str, _>("token_id").into(),
+            client_uuid: row.read::<&str, _>("client_uuid").into(),
+            token_hash: row.read::<&str, _>("token_hash").into(),
+            name: row.read::<&str, _>("name").into(),
+            scopes: Scopes::from_db(row.read::<&str, _>("scopes")),
+            expires_at: row.read::<Option<i64>, _>("expires_at").map(|t| t as u64),
+            created_at: row.read::<i64, _>("created_at") as u64,
+        }
+    }
+
+    pub fn write_to_db(&self, connection: &Connection) {
+        let query = "INSERT INTO tokens (token_id, client_uuid, name, token_hash, scopes, expires_at, created_at) VALUES (?, ?, ?, ?, ?, ?, ?)";
+        let mut statement = connection.prepare(query).unwrap();
+        statement
+            .bind_iter::<_, (_, Value)>([
+                (1, self.token_id.as_ref().into()),
+                (2, self.client_uuid.as_ref().into()),
+                (3, self.name.as_str().into()),
+                (4, self.token_hash.as_ref().into()),
+                (5, self.scopes.to_db().into()),
+                (6, self.expires_at.map(|t| t as i64).into()),
+                (7, (self.created_at as i64).into()),
+            ])
+            .unwrap();
+        let _ = statement.next();
+    }
+
+    pub fn get(connection: &Connection, token_id: &str) -> Option<Self> {
+        let query = "SELECT * FROM tokens WHERE token_id = ?";
+        connection
+            .prepare(query)
+            .unwrap()
+            .into_iter()
+            .bind((1, token_id))
+            .unwrap()
+            .map(|row| Self::from_db_row(row.unwrap()))
+            .next()
+    }
+
+    // Oldest first
+    pub fn for_client(connection: &Connection, client_uuid: &str) -> Vec<Self> {
+        let query = "SELECT * FROM tokens WHERE client_uuid = ? ORDER BY created_at, rowid";
+        connection
+            .prepare(query)
+            .unwrap()
+            .into_iter()
+            .bind((1, client_uuid))
+            .unwrap()
+            .map(|row| Self::from_db_row(row.unwrap()))
+            .collect()
+    }
+
+    // Returns whether the client had a token with that id
+    pub fn revoke(connection: &Connection, client_uuid: &str, token_id: &str) -> bool {
+        let query = "DELETE FROM tokens WHERE client_uuid = ? AND token_id = ?";
+        let mut statement = connection.prepare(query).unwrap();
+        statement
+            .bind_iter::<_, (_, Value)>([(1, client_uuid.into()), (2, token_id.into())])
+            .unwrap();
+        let _ = statement.next();
+        connection.change_count() > 0
+    }
+
+    pub fn get_id(&self) -> Arc<str> {
+        self.token_id.clone()
+    }
+
+    pub fn get_client_uuid(&self) -> Arc<str> {
+        self.client_uuid.clone()
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| unix_time() >= expires_at)
+    }
+
+    pub fn verify(&self, token: &str, key: &TokenKey) -> bool {
+        key.verify(token, &self.token_hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens_db() -> Connection {
+        let connection = sqlite::open(":memory:").unwrap();
+        connection
+            .execute("CREATE TABLE tokens (token_id TEXT PRIMARY KEY, client_uuid TEXT NOT NULL, name TEXT NOT NULL, token_hash TEXT NOT NULL, scopes TEXT NOT NULL, expires_at INTEGER, created_at INTEGER NOT NULL);")
+            .unwrap();
+        connection
+    }
+
+    #[test]
+    fn only_admins_grant_bot_tokens() {
+        let bot = Scopes::default().with(Scope::Bot);
+        let admin = Scopes::CLIENT.with(Scope::Admin);
+        assert!(admin.can_grant(&bot));
+        assert!(admin.can_grant(&bot.with(Scope::Read)));
+        assert!(!Scopes::CLIENT.can_grant(&bot));
+        assert!(!Scopes::CLIENT.can_grant(&Scopes::CLIENT.with(Scope::Admin)));
+        assert!(Scopes::CLIENT.can_grant(&Scopes::default().with(Scope::Read)));
+    }
+
+    #[test]
+    fn bot_tokens_are_prefixed() {
+        let connection = tokens_db();
+        let key = TokenKey {
+            key: vec![7; KEY_LEN],
+        };
+        let bot = Scopes::default().with(Scope::Bot);
+        let (info, token) = Token::create(&connection, &key, "owner".into(), "bot", bot, None);
+        assert!(token.starts_with(&format!("{BOT_PREFIX}_")));
+        assert_eq!(key.token_id(&token), info.get_id().as_ref());
+        let stored = Token::get(&connection, &info.get_id()).unwrap();
+        assert!(stored.verify(&token, &key));
+        assert!(stored.scopes.can_send() && !stored.scopes.can_manage_tokens());
+    }
+}
@@ -1,90 +1,374 @@
 use crate::{
     channel::{ClientChannel, ClientInteractions},
-    message::{ClientSend, Message as ServerMessage},
+    client::{Client, Presence},
+    health::{self, Listener},
+    http::percent_decode,
+    lockout::AuthError,
+    logging,
+    message::{Broadcast, ClientSend, Event, Message as ServerMessage, MessageOps},
+    metrics::METRICS,
+    ratelimit::RateLimit,
+    server::{unix_time, WsAuthMethods},
 };
 use anyhow::Result;
-use futures::executor::block_on;
 use futures_channel::mpsc::unbounded;
-use futures_util::{future, pin_mut, StreamExt, TryStreamExt};
-use std::{net::SocketAddr, sync::Arc};
+use futures_util::{future, pin_mut, SinkExt, StreamExt, TryStreamExt};
+use serde::Deserialize;
+use std::{
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 use tokio::{
     net::{TcpListener, TcpStream},
+    runtime::Handle,
     sync::Mutex,
+    time::{sleep, timeout},
 };
-use tokio_tungstenite::tungstenite::{
-    handshake::server::{Request, Response},
-    http::{Response as http_Response, StatusCode},
+use tokio_tungstenite::{
+    tungstenite::{
+        handshake::server::{ErrorResponse, Request, Response},
+        http::{Response as http_Response, StatusCode},
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Message,
+    },
+    WebSocketStream,
 };
+use tracing::{debug, info, info_span, Instrument, Span};
+
+// How long a connection may take to send its token as the first message
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+// 4000 and up are free for applications, these mirror HTTP's 401 and 429
+const UNAUTHORIZED_CLOSE_CODE: u16 = 4401;
+const THROTTLED_CLOSE_CODE: u16 = 4429;
+
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+const TYPING_BURST: u32 = 5;
+const TYPING_PERIOD: Duration = Duration::from_secs(10);
+
+// Typing indicator of a single connection, expired server-side after TYPING_TIMEOUT
+#[derive(Default)]
+struct TypingState {
+    generation: AtomicU64,
+    active: AtomicBool,
+}
+
+impl TypingState {
+    // Returns whether the indicator changed and the generation of this update
+    fn set(&self, typing: bool) -> (bool, u64) {
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        (
+            self.active.swap(typing, Ordering::SeqCst) != typing,
+            generation,
+        )
+    }
+}
+
+fn presence_event(client: &Client, public: bool) -> Arc<str> {
+    let presence = if public {
+        client.presence.visible()
+    } else {
+        client.presence
+    };
+    Event::Presence {
+        uuid: client.get_uuid(),
+        presence,
+        status_text: if presence == Presence::Offline && client.presence == Presence::Invisible {
+            None
+        } else {
+            client.status_text.clone()
+        },
+        last_seen: client.last_seen,
+    }
+    .to_payload()
+}
+
+// Sessions of the client itself see its real presence, everyone else the visible one
+async fn broadcast_presence(client_channel: &Mutex<ClientChannel>, client: &Client) {
+    let peers = client_channel
+        .lock()
+        .await
+        .request(ClientInteractions::WsGetConnectedClients)
+        .await
+        .connected_clients()
+        .unwrap();
+    let started = Instant::now();
+    let own = presence_event(client, false);
+    let public = presence_event(client, true);
+    for peer in peers.values() {
+        if let Some(tx) = peer.tx.as_ref() {
+            let payload = if peer.get_uuid() == client.get_uuid() {
+                own.clone()
+            } else {
+                public.clone()
+            };
+            let _ = tx.unbounded_send(payload);
+        }
+    }
+    METRICS.broadcast.observe(started.elapsed());
+}
+
+fn typing_event(author_uuid: Arc<str>, typing: bool) -> Arc<str> {
+    Event::Typing {
+        author_uuid,
+        typing,
+        expires_in: TYPING_TIMEOUT.as_secs(),
+    }
+    .to_payload()
+}
+
+async fn send_to_all(client_channel: &Mutex<ClientChannel>, payload: Arc<str>) {
+    let peers = client_channel
+        .lock()
+        .await
+        .request(ClientInteractions::WsGetConnectedClients)
+        .await
+        .connected_clients()
+        .unwrap();
+    let started = Instant::now();
+    peers
+        .values()
+        .filter_map(|client| client.tx.as_ref())
+        .for_each(|tx| {
+            let _ = tx.unbounded_send(payload.clone());
+        });
+    METRICS.broadcast.observe(started.elapsed());
+}
+
+// Only typing indicators go to peers, none are sent for invisible sessions or
+// those of deleted accounts. Reactions and messages are still relayed, sending
+// them reveals the client.
+async fn send_to_peers(client_channel: &Mutex<ClientChannel>, addr: SocketAddr, payload: Arc<str>) {
+    let peers = client_channel
+        .lock()
+        .await
+        .request(ClientInteractions::WsGetConnectedClients)
+        .await
+        .connected_clients()
+        .unwrap();
+    if peers
+        .get(&addr)
+        .is_none_or(|client| client.presence == Presence::Invisible)
+    {
+        return;
+    }
+    let started = Instant::now();
+    peers
+        .iter()
+        .filter(|(peer_addr, _)| **peer_addr != addr)
+        .filter_map(|(_, client)| client.tx.as_ref())
+        .for_each(|tx| {
+            let _ = tx.unbounded_send(payload.clone());
+        });
+    METRICS.broadcast.observe(started.elapsed());
+}
+
+// Runs a future from the synchronous handshake and stream callbacks.
+// Unlike futures' executor this lets the runtime move other tasks off the
+// blocked worker, so the server loop answering the request keeps running.
+fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::task::block_in_place(|| Handle::current().block_on(future))
+}
+
+fn unauthorized(reason: &str) -> ErrorResponse {
+    http_Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header("WWW-Authenticate", "Bearer")
+        .body(Some(format!("{reason}\n")))
+        .unwrap()
+}
+
+fn too_many_attempts(retry_after: u64) -> ErrorResponse {
+    http_Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header("Retry-After", retry_after)
+        .body(Some("Too Many Failed Attempts\n".to_owned()))
+        .unwrap()
+}
+
+fn offered_protocol(req: &Request) -> Option<&str> {
+    req.headers()
+        .get("Sec-WebSocket-Protocol")?
+        .to_str()
+        .ok()?
+        .split(',')
+        .map(str::trim)
+        .next()
+}
+
+fn protocol_token(req: &Request) -> Option<String> {
+    let mut values = req
+        .headers()
+        .get("Sec-WebSocket-Protocol")?
+        .to_str()
+        .ok()?
+        .split(',')
+        .map(str::trim);
+    (values.next()? == "Authorization")
+        .then(|| values.next())?
+        .map(str::to_string)
+}
+
+fn bearer_token(req: &Request) -> Option<String> {
+    let value = req.headers().get("Authorization")?.to_str().ok()?;
+    let (scheme, token) = value.trim().split_once(' ')?;
+    scheme
+        .eq_ignore_ascii_case("Bearer")
+        .then(|| token.trim().to_string())
+}
+
+// Clients may percent-encode the token like any other query value
+fn query_token(req: &Request) -> Option<String> {
+    req.uri()
+        .query()?
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("token="))
+        .map(percent_decode)
+}
+
+// Token from the first enabled method the request uses
+fn request_token(req: &Request, methods: &WsAuthMethods) -> Option<String> {
+    [
+        (
+            methods.protocol,
+            protocol_token as fn(&Request) -> Option<String>,
+        ),
+        (methods.bearer, bearer_token),
+        (methods.query, query_token),
+    ]
+    .into_iter()
+    .filter(|(enabled, _)| *enabled)
+    .find_map(|(_, method)| method(req))
+    .filter(|token| !token.is_empty())
+}
+
+async fn validate(
+    client_channel: &Mutex<ClientChannel>,
+    addr: SocketAddr,
+    token: String,
+) -> Result<Client, AuthError> {
+    client_channel
+        .lock()
+        .await
+        .request(ClientInteractions::WsValidateClient {
+            ip: addr.ip(),
+            token,
+        })
+        .await
+        .client_validation()
+}
+
+#[derive(Deserialize)]
+struct AuthMessage {
+    token: String,
+}
+
+async fn first_message_auth(
+    ws_stream: &mut WebSocketStream<TcpStream>,
+    addr: SocketAddr,
+    client_channel: &Mutex<ClientChannel>,
+) -> Result<Client, AuthError> {
+    let Ok(Some(Ok(message))) = timeout(AUTH_TIMEOUT, ws_stream.next()).await else {
+        return Err(AuthError::Invalid);
+    };
+    let Ok(auth) = serde_json::from_slice::<AuthMessage>(&message.into_data()) else {
+        return Err(AuthError::Invalid);
+    };
+    validate(client_channel, addr, auth.token).await
+}
+
+fn expire_typing(
+    state: Arc<TypingState>,
+    generation: u64,
+    author_uuid: Arc<str>,
+    addr: SocketAddr,
+    client_channel: Arc<Mutex<ClientChannel>>,
+) {
+    tokio::spawn(async move {
+        sleep(TYPING_TIMEOUT).await;
+        if state.generation.load(Ordering::SeqCst) == generation
+            && state.active.swap(false, Ordering::SeqCst)
+        {
+            send_to_peers(&client_channel, addr, typing_event(author_uuid, false)).await;
+        }
+    });
+}
 
 async fn handle_connection(
     raw_stream: TcpStream,
     addr: SocketAddr,
+    auth_methods: WsAuthMethods,
     client_channel: Arc<Mutex<ClientChannel>>,
 ) {
-    println!("Incoming TCP connection from: {}", addr);
+    debug!("incoming tcp connection");
+    let mut authenticated = None;
+    #[allow(clippy::result_large_err)]
     let callback = |req: &Request, mut response: Response| {
-        let protoheaders = req.headers().get("Sec-WebSocket-Protocol").unwrap();
-        let binding = protoheaders
-            .to_str()
-            .unwrap()
-            .split(", ")
-            .map(|v| v.to_owned())
-            .collect::<Vec<String>>();
-        let mut protocol_segments = binding.iter();
-        let token = match protocol_segments.next() {
-            Some(t) => {
-                if t.eq("Authorization") {
-                    protocol_segments.next()
-                } else {
-                    None
+        match request_token(req, &auth_methods) {
+            Some(token) => match block_on(validate(&client_channel, addr, token)) {
+                Ok(client) => authenticated = Some(client),
+                Err(AuthError::Invalid) => return Err(unauthorized("Invalid Token")),
+                Err(AuthError::Throttled(retry_after)) => {
+                    return Err(too_many_attempts(retry_after))
                 }
-            }
-            _ => None,
-        };
-        match token {
-            None => {
-                return Err(http_Response::builder()
-                    .status(StatusCode::NETWORK_AUTHENTICATION_REQUIRED)
-                    .body(Some("No Authorization Token ?Provided".to_owned()))
-                    .unwrap());
-            }
-            Some(token) => {
-                let auth = block_on(async {
-                    let mut channel = client_channel.lock().await;
-                    let client = channel
-                        .request(ClientInteractions::WsValidateClient(token.clone()))
-                        .await
-                        .client_validation();
-                    if let Some(client_inner) = client {
-                        channel
-                            .request(ClientInteractions::WsClientConnected {
-                                addr,
-                                client: client_inner,
-                            })
-                            .await;
-                        drop(channel);
-                        return true;
-                    }
-                    drop(channel);
-                    false
-                });
-                if !auth {
-                    return Err(http_Response::builder()
-                        .status(StatusCode::NETWORK_AUTHENTICATION_REQUIRED)
-                        .body(Some("No Invalid Token Provided".to_owned()))
-                        .unwrap());
-                }
-            }
+            },
+            None if auth_methods.first_message => {}
+            None => return Err(unauthorized("No Authorization Token Provided")),
+        }
+        // Only a protocol the client offered may be selected
+        if offered_protocol(req) == Some("Authorization") {
+            let headers = response.headers_mut();
+            headers.insert("Sec-Websocket-Protocol", "Authorization".parse().unwrap());
         }
-
-        let headers = response.headers_mut();
-        headers.insert("Sec-Websocket-Protocol", "Authorization".parse().unwrap());
         Ok(response)
     };
 
-    let ws_stream = tokio_tungstenite::accept_hdr_async(raw_stream, callback)
+    let mut ws_stream = match tokio_tungstenite::accept_hdr_async(raw_stream, callback).await {
+        Ok(ws_stream) => ws_stream,
+        Err(e) => {
+            debug!(error = %e, "failed websocket handshake");
+            return;
+        }
+    };
+    let client = match authenticated {
+        Some(client) => client,
+        None => match first_message_auth(&mut ws_stream, addr, &client_channel).await {
+            Ok(client) => {
+                let ack = Event::Authenticated {
+                    uuid: client.get_uuid(),
+                };
+                let _ = ws_stream
+                    .send(Message::text(ack.to_payload().as_ref()))
+                    .await;
+                client
+            }
+            Err(e) => {
+                let (code, reason) = match e {
+                    AuthError::Invalid => (UNAUTHORIZED_CLOSE_CODE, "Unauthorized".to_string()),
+                    AuthError::Throttled(retry_after) => (
+                        THROTTLED_CLOSE_CODE,
+                        format!("Too Many Failed Attempts, Retry After {retry_after}s"),
+                    ),
+                };
+                let _ = ws_stream
+                    .close(Some(CloseFrame {
+                        code: CloseCode::from(code),
+                        reason: reason.into(),
+                    }))
+                    .await;
+                return;
+            }
+        },
+    };
+    client_channel
+        .lock()
         .await
-        .expect("Error during the websocket handshake occurred");
+        .request(ClientInteractions::WsClientConnected { addr, client })
+        .await;
 
     let connected_clients = client_channel
         .lock()
@@ -93,11 +377,12 @@ async fn handle_connection(
         .await
         .connected_clients();
 
-    let message = ServerMessage::new_server_message(format!(
-        "<<!{}>> joined the server",
-        connected_clients.unwrap().get(&addr).unwrap().get_uuid()
-    ))
-    .to_message();
+    let client = connected_clients.unwrap().get(&addr).unwrap().clone();
+    let uuid = client.get_uuid();
+    Span::current().record("client", uuid.as_ref());
+    info!(guest = client.is_guest, "client connected");
+    let message =
+        ServerMessage::new_server_message(format!("<<!{}>> joined the server", uuid)).to_payload();
 
     let (tx, rx) = unbounded();
     client_channel
@@ -105,65 +390,202 @@ async fn handle_connection(
         .await
         .request(ClientInteractions::WsSetClientConnectedTx { addr, tx })
         .await;
-    let connected_clients = client_channel
-        .lock()
-        .await
-        .request(ClientInteractions::WsGetConnectedClients)
-        .await
-        .connected_clients()
-        .unwrap();
+    if client.presence != Presence::Invisible {
+        let connected_clients = client_channel
+            .lock()
+            .await
+            .request(ClientInteractions::WsGetConnectedClients)
+            .await
+            .connected_clients()
+            .unwrap();
 
-    connected_clients.values().for_each(|client| {
-        let _ = client.tx.as_ref().unwrap().unbounded_send(message.clone());
-    });
+        connected_clients.values().for_each(|client| {
+            let _ = client.tx.as_ref().unwrap().unbounded_send(message.clone());
+        });
+        broadcast_presence(&client_channel, &client).await;
+    }
     let (outgoing, incoming) = ws_stream.split();
 
+    let scopes = client.scopes;
+    let is_guest = client.is_guest;
+    let typing = Arc::new(TypingState::default());
+    let mut typing_limit = RateLimit::new(TYPING_BURST, TYPING_PERIOD);
     let broadcast_incoming = incoming.try_for_each(|msg| {
-        if let Some(client_message) = ClientSend::parse(msg.clone().into_data()) {
-            println!(
-                "Received a message from {}: {}",
-                addr, client_message.message
-            );
-            let peers = block_on(async {
-                client_channel
-                    .lock()
-                    .await
-                    .request(ClientInteractions::WsGetConnectedClients)
-                    .await
-                    .connected_clients()
-                    .unwrap()
-            });
-            let broadcast_recipients = peers.values();
-            let sender = peers
-                .iter()
-                .filter(|(peer_addr, _)| peer_addr == &&addr)
-                .map(|(_, client)| client.get_uuid())
-                .next()
-                .unwrap();
-            let server_message = ServerMessage::new(client_message.message, sender);
-            let mentions = server_message.mentions();
-
-            for recp in broadcast_recipients {
-                let m = if mentions.contains(&recp.get_uuid().to_string()) {
-                    server_message.clone().set_mention()
-                } else {
-                    server_message.clone()
+        let client_message = match ClientSend::parse(msg.clone().into_data()) {
+            Some(client_message) => client_message,
+            None => return future::err(tokio_tungstenite::tungstenite::Error::ConnectionClosed),
+        };
+        // Read only tokens receive everything but can not take part
+        if !scopes.can_send() {
+            return future::ok(());
+        }
+        match client_message.op {
+            MessageOps::Typing => {
+                if typing_limit.try_acquire() {
+                    let is_typing = client_message.typing.unwrap();
+                    let (changed, generation) = typing.set(is_typing);
+                    if changed {
+                        block_on(send_to_peers(
+                            &client_channel,
+                            addr,
+                            typing_event(uuid.clone(), is_typing),
+                        ));
+                    }
+                    if is_typing {
+                        expire_typing(
+                            typing.clone(),
+                            generation,
+                            uuid.clone(),
+                            addr,
+                            client_channel.clone(),
+                        );
+                    }
+                }
+            }
+            MessageOps::SetPresence => {
+                // Clear the indicator while peers may still see it
+                if client_message.presence == Some(Presence::Invisible) && typing.set(false).0 {
+                    block_on(send_to_peers(
+                        &client_channel,
+                        addr,
+                        typing_event(uuid.clone(), false),
+                    ));
+                }
+                let client = block_on(async {
+                    client_channel
+                        .lock()
+                        .await
+                        .request(ClientInteractions::WsSetPresence {
+                            addr,
+                            presence: client_message.presence.unwrap(),
+                            status_text: client_message.status_text,
+                        })
+                        .await
+                        .updated_client()
+                });
+                if let Some(client) = client {
+                    block_on(broadcast_presence(&client_channel, &client));
+                }
+            }
+            MessageOps::AddReaction | MessageOps::RemoveReaction => {
+                let message_uuid = client_message.get_message_uuid().unwrap();
+                let added = matches!(client_message.op, MessageOps::AddReaction);
+                let reaction = block_on(async {
+                    client_channel
+                        .lock()
+                        .await
+                        .request(ClientInteractions::WsReact {
+                            client_uuid: uuid.clone(),
+                            message_uuid: message_uuid.clone(),
+                            emoji: client_message.emoji.unwrap(),
+                            added,
+                        })
+                        .await
+                        .reaction()
+                });
+                if let Some(reaction) = reaction {
+                    let event = Event::Reaction {
+                        message_uuid: message_uuid.into(),
+                        user_uuid: uuid.clone(),
+                        added,
+                        reaction,
+                    };
+                    block_on(send_to_all(&client_channel, event.to_payload()));
+                }
+            }
+            _ => {
+                if typing.set(false).0 {
+                    block_on(send_to_peers(
+                        &client_channel,
+                        addr,
+                        typing_event(uuid.clone(), false),
+                    ));
+                }
+                debug!(
+                    content = logging::message_content(&client_message.message),
+                    "received message"
+                );
+                let peers = block_on(async {
+                    client_channel
+                        .lock()
+                        .await
+                        .request(ClientInteractions::WsGetConnectedClients)
+                        .await
+                        .connected_clients()
+                        .unwrap()
+                });
+                let broadcast_recipients = peers.values();
+                let sender = peers
+                    .iter()
+                    .filter(|(peer_addr, _)| peer_addr == &&addr)
+                    .map(|(_, client)| client.get_uuid())
+                    .next()
+                    .unwrap();
+                let mut server_message = ServerMessage::new(client_message.message.clone(), sender);
+                server_message.is_guest_message = is_guest;
+                if let Some(parent_uuid) = client_message.get_parent_uuid() {
+                    server_message = server_message.reply_to(
+                        parent_uuid.into(),
+                        client_message.thread,
+                        client_message.mention_parent,
+                    );
+                }
+                let stored = block_on(async {
+                    client_channel
+                        .lock()
+                        .await
+                        .request(ClientInteractions::WsStoreMessage {
+                            message: server_message,
+                            attachments: client_message.attachments.clone(),
+                        })
+                        .await
+                        .stored_message()
+                });
+                let Some(server_message) = stored else {
+                    return future::ok(());
                 };
-                recp.tx
-                    .as_ref()
-                    .unwrap()
-                    .unbounded_send(m.to_message())
-                    .expect("Failed to Send Message to Peers");
+                let started = Instant::now();
+                let broadcast = Broadcast::new(&server_message);
+
+                // Sessions of a deleted account may already be closed
+                for recp in broadcast_recipients {
+                    let _ = recp
+                        .tx
+                        .as_ref()
+                        .unwrap()
+                        .unbounded_send(broadcast.payload_for(&recp.get_uuid()));
+                }
+                METRICS.broadcast.observe(started.elapsed());
             }
-            future::ok(())
-        } else {
-            future::err(tokio_tungstenite::tungstenite::Error::ConnectionClosed)
         }
+        future::ok(())
     });
-    let receive_from_others = rx.map(Ok).forward(outgoing);
+    // tungstenite frames own their text, so the shared payload is copied here
+    let receive_from_others = rx
+        .map(|payload| Message::text(payload.as_ref()))
+        .map(Ok)
+        .forward(outgoing);
 
-    pin_mut!(broadcast_incoming, receive_from_others);
-    future::select(broadcast_incoming, receive_from_others).await;
+    // Sessions are closed once their token expires
+    let expiry = async {
+        match client.expires_at {
+            Some(expires_at) => {
+                sleep(Duration::from_secs(expires_at.saturating_sub(unix_time()))).await
+            }
+            None => future::pending().await,
+        }
+    };
+
+    pin_mut!(broadcast_incoming, receive_from_others, expiry);
+    future::select(
+        future::select(broadcast_incoming, receive_from_others),
+        expiry,
+    )
+    .await;
+    info!("client disconnected");
+    if typing.set(false).0 {
+        send_to_peers(&client_channel, addr, typing_event(uuid.clone(), false)).await;
+    }
     let peers = client_channel
         .lock()
         .await
@@ -172,16 +594,23 @@ async fn handle_connection(
         .connected_clients()
         .unwrap();
 
+    // Deleting the account already removed the session
+    let Some(client) = peers.get(&addr).cloned() else {
+        return;
+    };
     let message = ServerMessage::new_server_message(format!(
         "<<!{}>> disconnected from the server",
-        peers.get(&addr).unwrap().get_uuid()
+        client.get_uuid()
     ))
-    .to_message();
+    .to_payload();
     client_channel
         .lock()
         .await
         .request(ClientInteractions::WsClientLeft { addr })
         .await;
+    if client.presence == Presence::Invisible {
+        return;
+    }
     let peers = client_channel
         .lock()
         .await
@@ -192,6 +621,11 @@ async fn handle_connection(
     peers.values().for_each(|client| {
         let _ = client.tx.as_ref().unwrap().unbounded_send(message.clone());
     });
+    if !peers.values().any(|peer| peer.get_uuid() == uuid) {
+        let mut client = client.offline();
+        client.last_seen = unix_time();
+        broadcast_presence(&client_channel, &client).await;
+    }
 }
 
 pub async fn websocket_main(mut client: ClientChannel) -> Result<()> {
@@ -202,12 +636,21 @@ pub async fn websocket_main(mut client: ClientChannel) -> Result<()> {
         .unwrap();
     let try_socket = TcpListener::bind(&addr).await;
     let listener = try_socket.expect("Failed to bind");
-    println!("Listening to WebSocket Requests on: {}", addr);
+    info!(%addr, "listening for websocket connections");
+    health::listener_bound(Listener::WebSocket);
+
+    let auth_methods = client
+        .request(ClientInteractions::WsAuthMethods)
+        .await
+        .auth_methods();
 
     let client_channel = Arc::new(Mutex::new(client));
     // Let's spawn the handling of each connection in a separate task.
     while let Ok((stream, addr)) = listener.accept().await {
-        tokio::spawn(handle_connection(stream, addr, client_channel.clone()));
+        let span = info_span!("connection", %addr, client = tracing::field::Empty);
+        tokio::spawn(
+            handle_connection(stream, addr, auth_methods, client_channel.clone()).instrument(span),
+        );
     }
 
     Ok(())